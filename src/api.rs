@@ -1,53 +1,200 @@
+use core::future::Future;
+use std::collections::HashSet;
 use std::iter;
+use std::time::Instant;
 
 use actix_web::body::SizedStream;
 use actix_web::http;
 use actix_web::http::header::HeaderName;
 use actix_web::rt;
 use actix_web::web;
+use actix_web::web::Bytes;
+use actix_web::HttpRequest;
 use actix_web::HttpResponse;
+use actix_web::ResponseError;
 use compact_str::CompactString;
 use dkregistry::v2::Client;
+use futures::future::BoxFuture;
+use futures::stream::BoxStream;
 use futures::stream::StreamExt;
 use futures::stream::TryStreamExt;
 use once_cell::sync::Lazy;
+use prometheus::register_histogram_vec;
 use prometheus::register_int_counter_vec;
+use prometheus::register_int_gauge;
+use prometheus::HistogramVec;
+use prometheus::IntCounter;
 use prometheus::IntCounterVec;
+use prometheus::IntGauge;
 use serde::Deserialize;
 use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
 use tracing::error;
+use tracing::info_span;
 use tracing::warn;
+use tracing::Instrument;
 
+use crate::cleanup::Cleaner;
+use crate::digest::Algorithm;
+use crate::digest::Digest;
 use crate::image::ImageName;
 use crate::image::ImageReference;
+use crate::jobs::Jobs;
+use crate::manifest::Platform;
+use crate::pin::Pin;
+use crate::pin::Pins;
+use crate::prefetch;
 use crate::storage::Manifest;
+use crate::storage::Origin;
 use crate::storage::Repository;
 use crate::upstream::Clients;
+use crate::upstream::InvalidationConfig;
 
+pub mod access_log;
+use access_log::CacheStatus;
+pub mod admin;
 pub mod error;
 use error::should_retry_without_namespace;
 use error::Error;
 pub mod stream;
 use stream::DigestCheckedStream;
+use stream::DigestMismatchError;
 
 pub struct RequestConfig {
 	repo: Repository,
 	upstream: Mutex<Clients>,
 	default_ns: CompactString,
-	check_cache_digest: bool
+	check_cache_digest: bool,
+	pins: Pins,
+	prefetch: prefetch::Background,
+	cleaner: Cleaner,
+	jobs: Jobs,
+	/// Blob references known to have been recorded in storage since startup
+	recorded_references: std::sync::Mutex<HashSet<String>>,
+	/// Storage paths of filtered indexes known to have been cached since startup
+	filtered_indexes: std::sync::Mutex<HashSet<String>>
 }
 
 impl RequestConfig {
-	pub fn new(repo: Repository, upstream: Clients, default_ns: CompactString, check_cache_digest: bool) -> Self {
-		Self { repo, upstream: Mutex::new(upstream), default_ns, check_cache_digest }
+	pub fn new(repo: Repository, upstream: Clients, default_ns: CompactString, check_cache_digest: bool, pins: Pins, prefetch: prefetch::Background, cleaner: Cleaner) -> Self {
+		Self {
+			repo,
+			upstream: Mutex::new(upstream),
+			default_ns,
+			check_cache_digest,
+			pins,
+			prefetch,
+			cleaner,
+			jobs: Jobs::default(),
+			recorded_references: Default::default(),
+			filtered_indexes: Default::default()
+		}
+	}
+
+	pub(crate) fn background_prefetch(&self) -> &prefetch::Background {
+		&self.prefetch
+	}
+
+	pub(crate) fn jobs(&self) -> &Jobs {
+		&self.jobs
+	}
+
+	pub(crate) async fn invalidation_config(&self) -> InvalidationConfig {
+		self.upstream.lock().await.invalidation_config()
+	}
+
+	/// The platforms that matter in `namespace`, or `default` if it doesn't say
+	pub(crate) async fn platforms(&self, namespace: &str, default: &[Platform]) -> Result<Vec<Platform>, Error> {
+		let mut upstream = self.upstream.lock().await;
+		let platforms = &upstream.get(namespace)?.platforms;
+		Ok(match platforms.is_empty() {
+			true => default.to_vec(),
+			false => platforms.clone()
+		})
 	}
 }
 
+/// Records in the background that `origin` references the blob at `storage_path`, returning the
+/// task that does so.  Unless `force` is set, this is skipped if we've already done so since
+/// startup.
+fn record_reference(config: &web::Data<RequestConfig>, storage_path: &str, origin: Origin, force: bool) -> Option<JoinHandle<()>> {
+	let key = crate::storage::reference_path(storage_path, &origin);
+	if (!config.recorded_references.lock().unwrap().insert(key.clone()) && !force) {
+		return None;
+	}
+	let config = config.clone();
+	let storage_path = storage_path.to_owned();
+	Some(rt::spawn(async move {
+		if let Err(error) = config.repo.add_reference(&storage_path, &origin).await {
+			error!(%error, storage_path, "Failed to record blob reference");
+			config.recorded_references.lock().unwrap().remove(&key);
+		}
+	}))
+}
+
 async fn authenticate_with_upstream(upstream: &mut Client, scope: &str) -> Result<(), dkregistry::errors::Error> {
 	upstream.authenticate(&[scope]).await?;
 	Ok(())
 }
 
+/// A client for `namespace`'s upstream, authenticated for `scope`.  Credentials from a Docker
+/// config file or credential helper are fetched again when they expire, and if upstream rejects
+/// them.
+async fn authenticated_client(config: &RequestConfig, namespace: &str, scope: &str) -> Result<crate::upstream::Client, Error> {
+	if let Err(error) = Clients::refresh_credentials(&config.upstream, namespace, false).await {
+		warn!(namespace, %error, "Failed to fetch fresh upstream credentials; using the old ones");
+	}
+	let mut upstream = config.upstream.lock().await.get(namespace)?.clone();
+	match upstream_request(namespace, "auth", authenticate_with_upstream(&mut upstream.client, scope)).await {
+		Err(e) if e.upstream_status() == Some(http::StatusCode::UNAUTHORIZED) && upstream.refreshes_credentials() => {
+			warn!(namespace, "Upstream rejected our credentials; fetching them again");
+			if let Err(error) = Clients::refresh_credentials(&config.upstream, namespace, true).await {
+				error!(namespace, %error, "Failed to fetch fresh upstream credentials");
+				return Err(e);
+			}
+			upstream = config.upstream.lock().await.get(namespace)?.clone();
+			upstream_request(namespace, "auth", authenticate_with_upstream(&mut upstream.client, scope)).await?;
+		},
+		result => result?
+	};
+	Ok(upstream)
+}
+
+/// Records how long a request to upstream took until it was answered, and whether it failed, by
+/// namespace and operation, in a span of its own.  Something not being found upstream isn't a
+/// failure.
+async fn upstream_request<T, E>(namespace: &str, operation: &'static str, request: impl Future<Output = Result<T, E>>) -> Result<T, Error>
+where
+	Error: From<E>
+{
+	static DURATION: Lazy<HistogramVec> = Lazy::new(|| register_histogram_vec!("upstream_request_duration_seconds", "Time taken by upstream registries to answer requests", &["namespace", "operation"]).unwrap());
+	static ERRORS: Lazy<IntCounterVec> = Lazy::new(|| register_int_counter_vec!("upstream_errors", "Number of requests to upstream registries that failed", &["namespace", "operation"]).unwrap());
+
+	let start = Instant::now();
+	let result = request
+		.instrument(info_span!("upstream_request", otel.kind = "client", namespace, operation))
+		.await
+		.map_err(Error::from);
+	DURATION.with_label_values(&[namespace, operation]).observe(start.elapsed().as_secs_f64());
+	if let Err(e) = &result {
+		if (e.status_code() != http::StatusCode::NOT_FOUND) {
+			ERRORS.with_label_values(&[namespace, operation]).inc();
+		}
+	}
+	result
+}
+
+/// Counts the bytes of manifests and blobs sent to clients, by namespace and by whether they were
+/// read from cache or pulled from upstream
+fn served_bytes(namespace: &str, cached: bool) -> IntCounter {
+	static SERVED_BYTES: Lazy<IntCounterVec> = Lazy::new(|| register_int_counter_vec!("served_bytes", "Total size of manifests and blobs served to clients", &["namespace", "source"]).unwrap());
+	let source = match cached {
+		true => "cache",
+		false => "upstream"
+	};
+	SERVED_BYTES.with_label_values(&[namespace, source])
+}
+
 pub async fn root(config: web::Data<RequestConfig>, qstr: web::Query<ManifestQueryString>) -> Result<&'static str, Error> {
 	let mut upstream = { config.upstream.lock().await.get(qstr.ns.as_deref().unwrap_or_else(|| config.default_ns.as_ref()))?.client.clone() };
 	upstream.authenticate(&[]).await?;
@@ -61,14 +208,10 @@ pub struct ManifestRequest {
 }
 
 impl ManifestRequest {
-	fn http_path(&self) -> String {
-		format!("/{}/manifests/{}", self.image, self.reference)
-	}
-
 	fn storage_path(&self, ns: &str) -> String {
 		match self.image.as_ref().split('/').next() {
 			Some(part) if part == ns => format!("manifests/{}/{}", self.image, self.reference),
-			_ => format!("manifests/{}/{}/{}", ns, self.image, self.reference)
+			_ => crate::storage::manifest_path(ns, self.image.as_ref(), &self.reference.to_str())
 		}
 	}
 }
@@ -87,48 +230,146 @@ fn manifest_response(manifest: Manifest) -> HttpResponse {
 	response.body(manifest.manifest)
 }
 
-pub async fn manifest(req: web::Path<ManifestRequest>, qstr: web::Query<ManifestQueryString>, config: web::Data<RequestConfig>) -> Result<HttpResponse, Error> {
+pub async fn manifest(http_req: HttpRequest, req: web::Path<ManifestRequest>, qstr: web::Query<ManifestQueryString>, config: web::Data<RequestConfig>) -> Result<HttpResponse, Error> {
+	let (namespace, image) = split_image(qstr.ns.as_deref(), req.image.as_ref(), config.default_ns.as_ref());
+	access_log::record(&http_req, |details| details.resolved(namespace, image, &req.reference.to_str()));
+	let (manifest, cache_status) = match fetch_manifest(&config, namespace, image, &req.reference, &req.storage_path(namespace)).await {
+		Ok(v) => v,
+		Err(e) => {
+			access_log::record(&http_req, |details| details.upstream_failed(e.upstream_status()));
+			return Err(e);
+		}
+	};
+	access_log::record(&http_req, |details| details.served(cache_status));
+	let cached = cache_status.is_hit();
+	let (prefetch, filter_platforms) = {
+		let mut upstream = config.upstream.lock().await;
+		let client = upstream.get(namespace)?;
+		(client.prefetch, client.filter_indexes.then(|| client.platforms.clone()))
+	};
+	if (!cached && prefetch) {
+		prefetch::spawn_referenced(&config, namespace, image, manifest.manifest.as_ref());
+	}
+	// Requests by digest have to get exactly what they asked for
+	let manifest = match (filter_platforms, &req.reference) {
+		(Some(platforms), ImageReference::Tag(_)) if !platforms.is_empty() => filter_index(&config, namespace, image, manifest, &platforms, cached).await?,
+		_ => manifest
+	};
+	if (http_req.method() != http::Method::HEAD) {
+		served_bytes(namespace, cached).inc_by(manifest.manifest.len() as u64);
+	}
+	Ok(manifest_response(manifest))
+}
+
+/// Removes the children of an index that aren't for one of `platforms`.  That makes it a
+/// different document with a different digest, so it's also cached under that digest, for
+/// clients that request it by digest next.  That copy is written whenever the index comes from
+/// upstream; when it comes from cache, it's only written if it's missing, which is only checked
+/// the first time since startup.
+async fn filter_index(config: &RequestConfig, namespace: &str, image: &str, manifest: Manifest, platforms: &[Platform], cached: bool) -> Result<Manifest, Error> {
+	let Some(filtered) = crate::manifest::filter_index(manifest.manifest.as_ref(), platforms)? else {
+		return Ok(manifest);
+	};
+	let digest = Algorithm::Sha256.digest(&filtered).to_string();
+	let filtered = Manifest::new(filtered.into(), manifest.media_type, Some(digest.clone()));
+	let storage_path = crate::storage::manifest_path(namespace, image, &digest);
+	let known = !config.filtered_indexes.lock().unwrap().insert(storage_path.clone());
+	if (!cached || (!known && config.repo.read_unrecorded(&storage_path).await.is_err())) {
+		store_manifest(config, &storage_path, namespace, image, &filtered).await;
+	}
+	Ok(filtered)
+}
+
+async fn store_manifest(config: &RequestConfig, storage_path: &str, namespace: &str, image: &str, manifest: &Manifest) {
+	let body = serde_json::to_vec(manifest).unwrap();
+	let len = body.len().try_into().unwrap_or(i64::MAX);
+	if let Err(error) = config
+		.repo
+		.write(storage_path, futures::stream::iter(iter::once(Result::<_, std::io::Error>::Ok(body.into()))), len, &Origin::new(namespace, image))
+		.instrument(info_span!("storage_write", storage_path))
+		.await
+	{
+		error!(%error, "Failed to write manifest to storage");
+	}
+}
+
+/// Makes sure that `manifest.digest` is the digest of its body, filling it in if upstream didn't
+/// send one and correcting it if upstream sent the wrong one.  If it was requested by digest, it
+/// has to match that digest, or upstream sent us the wrong thing.
+fn check_manifest_digest(manifest: &mut Manifest, namespace: &str, image: &str, reference: &ImageReference) -> Result<(), Error> {
+	let algorithm = match (reference, manifest.digest.as_deref().and_then(|d| d.parse::<Digest>().ok())) {
+		(ImageReference::Digest(wanted), _) => wanted.algorithm(),
+		(ImageReference::Tag(_), Some(reported)) => reported.algorithm(),
+		(ImageReference::Tag(_), None) => Algorithm::Sha256
+	};
+	let actual = algorithm.digest(manifest.manifest.as_ref());
+	if let ImageReference::Digest(wanted) = reference {
+		if (&actual != wanted) {
+			return Err(Error::ManifestDigestMismatch(DigestMismatchError::new(wanted.clone(), actual)));
+		}
+	}
+	let actual = actual.to_string();
+	if let Some(reported) = manifest.digest.as_deref().filter(|d| *d != actual) {
+		warn!(namespace, image, %reference, reported, actual, "Upstream reported the wrong manifest digest; correcting it");
+	}
+	manifest.digest = Some(actual);
+	Ok(())
+}
+
+/// Reads a manifest from cache, or pulls it from upstream and caches it if it's missing or too
+/// old.  Also returns where it came from.
+pub(crate) async fn fetch_manifest(config: &RequestConfig, namespace: &str, image: &str, reference: &ImageReference, storage_path: &str) -> Result<(Manifest, CacheStatus), Error> {
 	static HIT_COUNTER: Lazy<IntCounterVec> = Lazy::new(|| register_int_counter_vec!("manifest_cache_hits", "Number of manifests read from cache", &["namespace"]).unwrap());
 	static MISS_COUNTER: Lazy<IntCounterVec> = Lazy::new(|| register_int_counter_vec!("manifest_cache_misses", "Number of manifest requests that went to upstream", &["namespace"]).unwrap());
 
-	let (namespace, image) = split_image(qstr.ns.as_deref(), req.image.as_ref(), config.default_ns.as_ref());
-
 	let max_age = config.upstream.lock().await.get(namespace)?.manifest_invalidation_time;
-	let storage_path = req.storage_path(namespace);
-	match config.repo.read(&storage_path, max_age).await {
+	let stale = match config.repo.read(storage_path, max_age).instrument(info_span!("storage_read", storage_path)).await {
 		Ok(stream) => {
 			let body = stream.into_inner().try_collect::<web::BytesMut>().await?;
-			let manifest = serde_json::from_slice(body.as_ref())?;
+			let mut manifest: Manifest = serde_json::from_slice(body.as_ref())?;
+			// Cached before we started filling this in
+			if (manifest.digest.is_none()) {
+				manifest.digest = Some(Algorithm::Sha256.digest(manifest.manifest.as_ref()).to_string());
+			}
 			HIT_COUNTER.with_label_values(&[namespace]).inc();
-			return Ok(manifest_response(manifest));
+			return Ok((manifest, CacheStatus::Hit));
 		},
-		Err(error) => warn!(path = req.http_path(), storage_path, %error, "Manifest not found in repository; pulling from upstream")
-	}
+		Err(error) => {
+			warn!(namespace, image, %reference, storage_path, %error, "Manifest not found in repository; pulling from upstream");
+			matches!(error, crate::storage::Error::ObjectTooOld(_))
+		}
+	};
 
 	MISS_COUNTER.with_label_values(&[namespace]).inc();
 	let manifest = {
-		let mut upstream = config.upstream.lock().await.get(namespace)?.clone();
-		authenticate_with_upstream(&mut upstream.client, &format!("repository:{}:pull", image)).await?;
-		let reference = req.reference.to_str();
-		let (manifest, media_type, digest) = match upstream.client.get_raw_manifest_and_metadata(image, reference.as_ref(), Some(namespace)).await {
-			Ok(v) => v,
-			Err(e) if should_retry_without_namespace(&e) => upstream.client.get_raw_manifest_and_metadata(image, reference.as_ref(), None).await?,
-			Err(e) => return Err(e.into())
-		};
-		Manifest::new(manifest, media_type, digest)
+		let upstream = authenticated_client(config, namespace, &format!("repository:{}:pull", image)).await?;
+		let reference = reference.to_str();
+		let (manifest, media_type, digest) = upstream_request(namespace, "manifest", async {
+			match upstream.client.get_raw_manifest_and_metadata(image, reference.as_ref(), Some(namespace)).await {
+				Err(e) if should_retry_without_namespace(&e) => upstream.client.get_raw_manifest_and_metadata(image, reference.as_ref(), None).await,
+				result => result
+			}
+		})
+		.await?;
+		let mut manifest = Manifest::new(manifest, media_type, digest);
+		check_manifest_digest(&mut manifest, namespace, image, reference)?;
+		manifest
 	};
 
-	let body = serde_json::to_vec(&manifest).unwrap();
-	let len = body.len().try_into().unwrap_or(i64::MAX);
-	if let Err(error) = config
-		.repo
-		.write(&storage_path, futures::stream::iter(iter::once(Result::<_, std::io::Error>::Ok(body.into()))), len)
-		.await
-	{
-		error!(%error, "Failed to write manifest to storage");
-	}
+	let cache_status = match stale {
+		true if cached_manifest_digest(config, storage_path).await == manifest.digest => CacheStatus::Revalidated,
+		true => CacheStatus::Stale,
+		false => CacheStatus::Miss
+	};
+	store_manifest(config, storage_path, namespace, image, &manifest).await;
+	Ok((manifest, cache_status))
+}
 
-	Ok(manifest_response(manifest))
+/// The digest of the manifest cached at `storage_path`, however old it is
+async fn cached_manifest_digest(config: &RequestConfig, storage_path: &str) -> Option<String> {
+	let body = config.repo.read_unrecorded(storage_path).await.ok()?.into_inner().try_collect::<web::BytesMut>().await.ok()?;
+	let manifest: Manifest = serde_json::from_slice(body.as_ref()).ok()?;
+	Some(manifest.digest.unwrap_or_else(|| Algorithm::Sha256.digest(manifest.manifest.as_ref()).to_string()))
 }
 
 #[derive(Debug, Deserialize)]
@@ -138,107 +379,211 @@ pub struct BlobRequest {
 }
 
 impl BlobRequest {
-	fn http_path(&self) -> String {
-		format!("/{}/blobs/{}", self.image, self.digest)
+	/// The requested digest, which has to be valid before anything is looked up by it
+	fn digest(&self) -> Result<Digest, Error> {
+		parse_digest(&self.digest)
 	}
+}
 
-	fn storage_path(&self) -> String {
-		let (method, hash) = self.digest.split_once(':').unwrap_or(("_", &self.digest));
-		let hash_prefix = hash.get(..2).unwrap_or("_");
-		let rest_of_hash = hash.get(2..).unwrap_or(hash);
-		format!("blobs/{method}/{hash_prefix}/{rest_of_hash}")
-	}
+/// Parses a digest using any supported algorithm, e.g. `sha256:...` or `sha512:...`
+pub(crate) fn parse_digest(digest: &str) -> Result<Digest, Error> {
+	digest.parse().map_err(|_| Error::InvalidDigest)
 }
 
-pub async fn blob(req: web::Path<BlobRequest>, qstr: web::Query<ManifestQueryString>, config: web::Data<RequestConfig>) -> Result<HttpResponse, Error> {
+fn count_blob_hit(namespace: &str) {
 	static HIT_COUNTER: Lazy<IntCounterVec> = Lazy::new(|| register_int_counter_vec!("blob_cache_hits", "Number of blobs read from cache", &["namespace"]).unwrap());
-	static MISS_COUNTER: Lazy<IntCounterVec> = Lazy::new(|| register_int_counter_vec!("blob_cache_misses", "Number of blob requests that went to upstream", &["namespace"]).unwrap());
+	HIT_COUNTER.with_label_values(&[namespace]).inc();
+}
 
-	let Some(wanted_digest_hex) = req.digest.strip_prefix("sha256:") else {
-		return Err(Error::InvalidDigest);
+pub async fn blob(http_req: HttpRequest, req: web::Path<BlobRequest>, qstr: web::Query<ManifestQueryString>, config: web::Data<RequestConfig>) -> Result<HttpResponse, Error> {
+	let wanted_digest = req.digest()?;
+	let digest = wanted_digest.to_string();
+	let (namespace, image) = split_image(qstr.ns.as_deref(), req.image.as_ref(), config.default_ns.as_ref());
+	access_log::record(&http_req, |details| details.resolved(namespace, image, &digest));
+
+	let storage_path = crate::storage::blob_path(&wanted_digest);
+	let (max_age, redirect) = {
+		let mut upstream = config.upstream.lock().await;
+		let client = upstream.get(namespace)?;
+		let user_agent = http_req.headers().get(http::header::USER_AGENT).and_then(|v| v.to_str().ok());
+		(client.blob_invalidation_time, client.redirect_blobs(user_agent))
 	};
-	let wanted_digest = {
-		let mut buf = [0u8; 256 / 8];
-		if (hex::decode_to_slice(wanted_digest_hex, &mut buf[..]).is_err()) {
-			return Err(Error::InvalidDigest);
+	if let Some(expires_in) = redirect {
+		match config.repo.presigned_url(storage_path.as_ref(), max_age, expires_in).await {
+			Ok(Some(url)) => {
+				count_blob_hit(namespace);
+				access_log::record(&http_req, |details| details.served(CacheStatus::Hit));
+				record_reference(&config, &storage_path, Origin::new(namespace, image), false);
+				return Ok(HttpResponse::TemporaryRedirect().insert_header((http::header::LOCATION, url)).finish());
+			},
+			Ok(None) => (),
+			// Missing or too old is an ordinary cache miss; the blob is pulled from upstream below
+			Err(error) if error.is_not_found() || matches!(error, crate::storage::Error::ObjectTooOld(_)) => (),
+			Err(error) => warn!(path = storage_path, %error, "Unable to redirect to blob in repository")
+		}
+	}
+
+	let blob = match fetch_blob(&config, namespace, image, &wanted_digest).await {
+		Ok(v) => v,
+		Err(e) => {
+			access_log::record(&http_req, |details| details.upstream_failed(e.upstream_status()));
+			return Err(e);
 		}
-		buf
 	};
+	access_log::record(&http_req, |details| details.served(blob.cache_status));
+	let served = served_bytes(namespace, blob.cache_status.is_hit());
+	let stream = blob.stream.inspect_ok(move |chunk| served.inc_by(chunk.len() as u64));
+	Ok(HttpResponse::Ok().body(SizedStream::new(blob.length, stream)))
+}
 
-	let (namespace, image) = split_image(qstr.ns.as_deref(), req.image.as_ref(), config.default_ns.as_ref());
+pub(crate) struct Blob {
+	pub length: u64,
+	pub stream: BoxStream<'static, Result<Bytes, std::io::Error>>,
+	/// Whether this is being read from cache, rather than pulled from upstream and cached as it's
+	/// read
+	pub cache_status: CacheStatus,
+	/// Completes once everything that serving this blob writes to storage has been written:  the
+	/// blob itself on a cache miss, and the record that this repository references it.  Nothing
+	/// needs to wait for this unless the process is about to exit.
+	pub written: BoxFuture<'static, Result<(), crate::storage::Error>>
+}
 
-	let storage_path = req.storage_path();
+/// Checks a blob read from cache against its digest as it's streamed to the client.  If it doesn't
+/// match, the stream ends in an error, so that the client sees an aborted response and retries
+/// instead of accepting it, and the blob is deleted so that the retry pulls it from upstream.
+fn verify_cached_blob(config: &web::Data<RequestConfig>, namespace: &str, storage_path: String, wanted_digest: &Digest, stream: BoxStream<'static, Result<Bytes, std::io::Error>>) -> BoxStream<'static, Result<Bytes, std::io::Error>> {
+	static CORRUPT_COUNTER: Lazy<IntCounterVec> = Lazy::new(|| register_int_counter_vec!("blob_cache_corrupt", "Number of cached blobs that didn't match their digest and were deleted", &["namespace"]).unwrap());
+
+	let config = config.clone();
+	let namespace = CompactString::from(namespace);
+	Box::pin(DigestCheckedStream::<_, crate::storage::Error, _>::new(stream, wanted_digest.clone()).map_err(move |error| {
+		if let crate::storage::Error::DataCorrupt(_) = &error {
+			error!(storage_path, %error, "Cached blob doesn't match its digest; deleting it");
+			CORRUPT_COUNTER.with_label_values(&[namespace.as_str()]).inc();
+			let (config, storage_path) = (config.clone(), storage_path.clone());
+			rt::spawn(async move {
+				if let Err(error) = config.repo.delete(&storage_path).await {
+					error!(storage_path, %error, "Failed to delete corrupt blob from storage");
+				}
+			});
+		}
+		std::io::Error::new(std::io::ErrorKind::Other, error)
+	}))
+}
+
+/// Reads a blob from cache, or starts pulling it from upstream and caching it if it's missing or
+/// too old.  In the latter case, it's only completely cached once the returned stream has been
+/// read to the end and [`Blob::written`] has completed.
+pub(crate) async fn fetch_blob(config: &web::Data<RequestConfig>, namespace: &str, image: &str, wanted_digest: &Digest) -> Result<Blob, Error> {
+	static MISS_COUNTER: Lazy<IntCounterVec> = Lazy::new(|| register_int_counter_vec!("blob_cache_misses", "Number of blob requests that went to upstream", &["namespace"]).unwrap());
+
+	let digest = wanted_digest.to_string();
+	let storage_path = crate::storage::blob_path(wanted_digest);
 	let max_age = config.upstream.lock().await.get(namespace)?.blob_invalidation_time;
-	match config.repo.read(storage_path.as_ref(), max_age).await {
-		Ok(stream) => match config.check_cache_digest {
-			true => {
-				let hash = stream::hash(stream.into_inner()).await?;
-				if (hash == wanted_digest) {
-					HIT_COUNTER.with_label_values(&[namespace]).inc();
-					let stream = config.repo.read(storage_path.as_ref(), max_age).await?;
-					return Ok(HttpResponse::Ok().body(SizedStream::new(stream.length(), stream.into_inner())));
+	let stale = match config.repo.read(storage_path.as_ref(), max_age).instrument(info_span!("storage_read", storage_path)).await {
+		Ok(stream) => {
+			count_blob_hit(namespace);
+			let reference = record_reference(config, &storage_path, Origin::new(namespace, image), false);
+			let length = stream.length();
+			let stream = match config.check_cache_digest {
+				true => verify_cached_blob(config, namespace, storage_path, wanted_digest, stream.into_inner()),
+				false => stream.into_inner()
+			};
+			let written = Box::pin(async move {
+				if let Some(reference) = reference {
+					// Failures are logged by the task itself, and only mean it'll be recorded again
+					let _ = reference.await;
 				}
-				error!(storage_path, "Digest mismatch");
-				config.repo.delete(storage_path.as_ref()).await?;
-			},
-			false => {
-				HIT_COUNTER.with_label_values(&[namespace]).inc();
-				let stream = config.repo.read(storage_path.as_ref(), max_age).await?;
-				return Ok(HttpResponse::Ok().body(SizedStream::new(stream.length(), stream.into_inner())));
-			}
+				Ok(())
+			});
+			return Ok(Blob { length, stream, cache_status: CacheStatus::Hit, written });
 		},
-		Err(error) => warn!(path = storage_path, %error, "Blob not found in repository; pulling from upstream")
+		Err(error) => {
+			warn!(path = storage_path, %error, "Blob not found in repository; pulling from upstream");
+			matches!(error, crate::storage::Error::ObjectTooOld(_))
+		}
 	};
 
 	MISS_COUNTER.with_label_values(&[namespace]).inc();
 	let response = {
-		let mut upstream = config.upstream.lock().await.get(namespace)?.clone();
-		authenticate_with_upstream(&mut upstream.client, &format!("repository:{}:pull", image)).await?;
-		match upstream.client.get_blob_response(image, req.digest.as_ref(), Some(namespace)).await {
-			Ok(v) => v,
-			Err(e) if should_retry_without_namespace(&e) => upstream.client.get_blob_response(image, req.digest.as_ref(), None).await?,
-			Err(e) => return Err(e.into())
-		}
+		let upstream = authenticated_client(config, namespace, &format!("repository:{}:pull", image)).await?;
+		upstream_request(namespace, "blob", async {
+			match upstream.client.get_blob_response(image, &digest, Some(namespace)).await {
+				Err(e) if should_retry_without_namespace(&e) => upstream.client.get_blob_response(image, &digest, None).await,
+				result => result
+			}
+		})
+		.await?
 	};
 
 	let len = response.size().ok_or(Error::MissingContentLength)?;
 	let (tx, rx) = async_broadcast::broadcast(16);
 	{
-		let mut stream = DigestCheckedStream::<_, crate::storage::Error, _>::new(response.stream().err_into::<crate::storage::Error>(), wanted_digest);
-		rt::spawn(async move {
-			while let Some(chunk) = stream.next().await {
-				let chunk = match chunk {
-					Ok(v) => Ok(v),
-					Err(error) => {
-						error!(%error, "Error reading from upstream");
-						Err(error)
+		let mut stream = DigestCheckedStream::<_, crate::storage::Error, _>::new(response.stream().err_into::<crate::storage::Error>(), wanted_digest.clone());
+		let http_path = format!("/{image}/blobs/{digest}");
+		// Created here rather than in the task, so that it's a child of the request's span
+		let span = info_span!("upstream_download", namespace, digest);
+		rt::spawn(
+			async move {
+				static IN_FLIGHT: Lazy<IntGauge> = Lazy::new(|| register_int_gauge!("upstream_blob_downloads_in_flight", "Number of blobs currently being pulled from upstream").unwrap());
+				IN_FLIGHT.inc();
+				while let Some(chunk) = stream.next().await {
+					let chunk = match chunk {
+						Ok(v) => Ok(v),
+						Err(error) => {
+							error!(%error, "Error reading from upstream");
+							Err(error)
+						}
+					};
+					let is_err = chunk.is_err();
+					if (tx.broadcast(chunk).await.is_err()) {
+						error!(path = http_path, "Readers for proxied blob request all closed");
+						break;
+					} else if is_err {
+						break;
 					}
-				};
-				let is_err = chunk.is_err();
-				if (tx.broadcast(chunk).await.is_err()) {
-					error!(path = req.http_path(), "Readers for proxied blob request all closed");
-					return;
-				} else if is_err {
-					return;
 				}
+				IN_FLIGHT.dec();
 			}
-		});
+			.instrument(span)
+		);
 	}
 
-	{
+	let written = {
 		let rx2 = rx.clone();
 		let config = config.clone();
-		rt::spawn(async move {
-			if let Err(error) = config.repo.write(storage_path.as_ref(), rx2, len.try_into().unwrap_or(i64::MAX)).await {
-				error!(%error, "Failed to write blob to storage");
-				if let Err(error) = config.repo.delete(storage_path.as_ref()).await {
-					error!(%error, "Failed to delete failed blob from storage");
+		let origin = Origin::new(namespace, image);
+		let span = info_span!("storage_write", storage_path);
+		rt::spawn(
+			async move {
+				if let Err(error) = config.repo.write(storage_path.as_ref(), rx2, len.try_into().unwrap_or(i64::MAX), &origin).await {
+					error!(%error, "Failed to write blob to storage");
+					if let Err(error) = config.repo.delete(storage_path.as_ref()).await {
+						error!(%error, "Failed to delete failed blob from storage");
+					}
+					return Err(error);
+				}
+				// The blob was just (re-)written, so any references recorded before may have been
+				// cleaned up along with an older copy of it
+				if let Some(reference) = record_reference(&config, &storage_path, origin, true) {
+					let _ = reference.await;
 				}
+				Ok(())
 			}
-		});
-	}
+			.instrument(span)
+		)
+	};
 
-	Ok(HttpResponse::Ok().body(SizedStream::new(len, rx.map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e)))))
+	Ok(Blob {
+		length: len,
+		stream: Box::pin(rx.map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))),
+		// Blobs are checked against their digest, so upstream can only have the same thing
+		cache_status: match stale {
+			true => CacheStatus::Revalidated,
+			false => CacheStatus::Miss
+		},
+		written: Box::pin(async move { written.await.unwrap_or_else(|e| Err(std::io::Error::new(std::io::ErrorKind::Other, e).into())) })
+	})
 }
 
 #[inline]
@@ -260,11 +605,33 @@ pub async fn delete_manifest(req: web::Path<ManifestRequest>, qstr: web::Query<M
 }
 
 pub async fn delete_blob(req: web::Path<BlobRequest>, config: web::Data<RequestConfig>) -> Result<&'static str, Error> {
-	let storage_path = req.storage_path();
+	let storage_path = crate::storage::blob_path(&req.digest()?);
 	config.repo.delete(storage_path.as_ref()).await?;
 	Ok("")
 }
 
+pub async fn list_pins(config: web::Data<RequestConfig>) -> Result<web::Json<Vec<Pin>>, Error> {
+	Ok(web::Json(config.pins.list(&config.repo).await?))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PinRequest {
+	pin: Pin
+}
+
+pub async fn pin(req: web::Path<PinRequest>, config: web::Data<RequestConfig>) -> Result<&'static str, Error> {
+	config.pins.add(&config.repo, &req.pin).await?;
+	Ok("")
+}
+
+pub async fn unpin(req: web::Path<PinRequest>, config: web::Data<RequestConfig>) -> Result<&'static str, Error> {
+	if (config.pins.is_configured(&req.pin)) {
+		return Err(Error::ConfiguredPin);
+	}
+	config.pins.remove(&config.repo, &req.pin).await?;
+	Ok("")
+}
+
 #[cfg(test)]
 mod tests {
 	use super::*;
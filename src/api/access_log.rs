@@ -0,0 +1,246 @@
+use core::future::Future;
+use core::iter;
+use core::pin::Pin;
+use std::io::Write;
+use std::sync::mpsc::SyncSender;
+use std::time::Instant;
+use std::time::SystemTime;
+
+use actix_web::body::BodySize;
+use actix_web::body::MessageBody;
+use actix_web::dev::Service;
+use actix_web::dev::ServiceRequest;
+use actix_web::dev::ServiceResponse;
+use actix_web::http::StatusCode;
+use actix_web::web::Bytes;
+use actix_web::HttpMessage;
+use actix_web::HttpRequest;
+use clap::ValueEnum;
+use compact_str::CompactString;
+use futures::task::Context;
+use futures::task::Poll;
+use once_cell::sync::Lazy;
+use pin_project::pin_project;
+use pin_project::pinned_drop;
+use prometheus::register_int_counter;
+use prometheus::IntCounter;
+use serde::Serialize;
+
+/// How many records can be waiting to be written before more are dropped
+const QUEUE_LENGTH: usize = 4096;
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum Format {
+	/// One line per request in Apache's combined log format
+	Apache,
+	/// One JSON object per line per request, on stdout; everything else is logged to stderr
+	Json
+}
+
+/// Where a manifest or blob that was served came from
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum CacheStatus {
+	/// Read from cache
+	Hit,
+	/// Pulled from upstream, because it wasn't cached
+	Miss,
+	/// Pulled from upstream, because the cached copy was too old, and upstream had something
+	/// different
+	Stale,
+	/// Pulled from upstream, because the cached copy was too old, and upstream still had the same
+	/// thing
+	Revalidated
+}
+
+impl CacheStatus {
+	pub fn is_hit(&self) -> bool {
+		*self == Self::Hit
+	}
+}
+
+/// What a request to the registry resolved to, as filled in by its handler
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct Details {
+	namespace: Option<CompactString>,
+	image: Option<CompactString>,
+	/// The tag or digest of a manifest, or the digest of a blob
+	reference: Option<CompactString>,
+	cache_status: Option<CacheStatus>,
+	upstream_status: Option<u16>
+}
+
+impl Details {
+	pub fn resolved(&mut self, namespace: &str, image: &str, reference: &str) {
+		self.namespace = Some(namespace.into());
+		self.image = Some(image.into());
+		self.reference = Some(reference.into());
+	}
+
+	pub fn served(&mut self, cache_status: CacheStatus) {
+		self.cache_status = Some(cache_status);
+		if (!cache_status.is_hit()) {
+			self.upstream_status = Some(StatusCode::OK.as_u16());
+		}
+	}
+
+	pub fn upstream_failed(&mut self, status: Option<StatusCode>) {
+		self.upstream_status = status.map(|s| s.as_u16());
+	}
+}
+
+/// Fills in the details of `req` for the access log
+pub fn record(req: &HttpRequest, f: impl FnOnce(&mut Details)) {
+	let mut extensions = req.extensions_mut();
+	match extensions.get_mut::<Details>() {
+		Some(details) => f(details),
+		None => {
+			let mut details = Details::default();
+			f(&mut details);
+			extensions.insert(details);
+		}
+	}
+}
+
+/// An access log record, written once the response body has been sent, or the client has gone
+/// away
+#[derive(Debug, Serialize)]
+struct Record {
+	time: String,
+	remote_addr: Option<String>,
+	method: String,
+	path: String,
+	user_agent: Option<String>,
+	status: u16,
+	#[serde(flatten)]
+	details: Details,
+	bytes_sent: u64,
+	duration_seconds: f64,
+	#[serde(skip)]
+	start: Instant
+}
+
+impl Record {
+	fn new<B>(response: &ServiceResponse<B>, time: SystemTime, start: Instant) -> Self {
+		let req = response.request();
+		Self {
+			time: humantime::format_rfc3339_millis(time).to_string(),
+			remote_addr: req.connection_info().realip_remote_addr().map(str::to_owned),
+			method: req.method().to_string(),
+			path: req.uri().to_string(),
+			user_agent: req.headers().get(actix_web::http::header::USER_AGENT).and_then(|v| v.to_str().ok()).map(str::to_owned),
+			status: response.status().as_u16(),
+			details: req.extensions().get::<Details>().cloned().unwrap_or_default(),
+			bytes_sent: 0,
+			duration_seconds: 0.0,
+			start
+		}
+	}
+
+	fn write(mut self, bytes_sent: u64) {
+		self.bytes_sent = bytes_sent;
+		self.duration_seconds = self.start.elapsed().as_secs_f64();
+		if let Ok(line) = serde_json::to_string(&self) {
+			send(line);
+		}
+	}
+}
+
+/// Queues a line to be written to stdout by a thread of its own, so that a slow reader of stdout
+/// holds up nothing but the access log.  If that thread falls too far behind, lines are dropped.
+fn send(line: String) {
+	static DROPPED: Lazy<IntCounter> = Lazy::new(|| register_int_counter!("access_log_dropped_records", "Number of access log records dropped because stdout wasn't keeping up").unwrap());
+	static QUEUE: Lazy<SyncSender<String>> = Lazy::new(|| {
+		let (tx, rx) = std::sync::mpsc::sync_channel::<String>(QUEUE_LENGTH);
+		std::thread::Builder::new()
+			.name("access-log".to_owned())
+			.spawn(move || {
+				let mut stdout = std::io::BufWriter::new(std::io::stdout().lock());
+				// Flushed whenever the queue is empty, so that nothing is held back for long
+				while let Ok(line) = rx.recv() {
+					let result = iter::once(line).chain(rx.try_iter()).try_for_each(|line| writeln!(stdout, "{line}")).and_then(|_| stdout.flush());
+					if (result.is_err()) {
+						return;
+					}
+				}
+			})
+			.unwrap();
+		tx
+	});
+
+	if (QUEUE.try_send(line).is_err()) {
+		DROPPED.inc();
+	}
+}
+
+/// A response body that counts the bytes taken from it, and writes the access log record when
+/// it's dropped
+#[pin_project(PinnedDrop)]
+pub struct LoggedBody<B> {
+	#[pin]
+	body: B,
+	record: Option<Record>,
+	bytes_sent: u64
+}
+
+impl<B: MessageBody> MessageBody for LoggedBody<B> {
+	type Error = B::Error;
+
+	fn size(&self) -> BodySize {
+		self.body.size()
+	}
+
+	fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Bytes, Self::Error>>> {
+		let this = self.project();
+		let poll = this.body.poll_next(cx);
+		if let Poll::Ready(Some(Ok(chunk))) = &poll {
+			*this.bytes_sent += chunk.len() as u64;
+		}
+		poll
+	}
+}
+
+#[pinned_drop]
+impl<B> PinnedDrop for LoggedBody<B> {
+	fn drop(self: Pin<&mut Self>) {
+		let this = self.project();
+		if let Some(record) = this.record.take() {
+			record.write(*this.bytes_sent);
+		}
+	}
+}
+
+/// Middleware, for use with `wrap_fn`, that writes a JSON access log record for each request if
+/// `format` calls for it
+pub fn log<S, B>(format: Format, req: ServiceRequest, srv: &S) -> impl Future<Output = Result<ServiceResponse<LoggedBody<B>>, actix_web::Error>>
+where
+	S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>
+{
+	let (time, start) = (SystemTime::now(), Instant::now());
+	let response = srv.call(req);
+	async move {
+		let response = response.await?;
+		let record = match format {
+			Format::Apache => None,
+			Format::Json => Some(Record::new(&response, time, start))
+		};
+		Ok(response.map_body(|_, body| LoggedBody { body, record, bytes_sent: 0 }))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn cache_status_names() {
+		assert_eq!(serde_json::to_string(&CacheStatus::Revalidated).unwrap(), "\"REVALIDATED\"");
+		let mut details = Details::default();
+		details.resolved("docker.io", "library/busybox", "latest");
+		details.served(CacheStatus::Miss);
+		let json = serde_json::to_value(&details).unwrap();
+		assert_eq!(json["namespace"], "docker.io");
+		assert_eq!(json["cache_status"], "MISS");
+		assert_eq!(json["upstream_status"], 200);
+	}
+}
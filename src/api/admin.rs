@@ -0,0 +1,371 @@
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::time::SystemTime;
+
+use actix_web::http;
+use actix_web::rt;
+use actix_web::web;
+use actix_web::HttpResponse;
+use futures::stream::TryStreamExt;
+use serde::Deserialize;
+use serde::Serialize;
+use tracing::error;
+
+use super::error::Error;
+use super::parse_digest;
+use super::split_image;
+use super::ManifestQueryString;
+use super::RequestConfig;
+use crate::cleanup;
+use crate::jobs::Job;
+use crate::manifest::References;
+use crate::purge;
+use crate::purge::Cascade;
+use crate::purge::Target;
+use crate::storage;
+use crate::storage::ObjectInfo;
+
+const DEFAULT_PAGE_SIZE: usize = 100;
+const MAX_PAGE_SIZE: usize = 1000;
+
+const fn default_page_size() -> usize {
+	DEFAULT_PAGE_SIZE
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PageQuery {
+	#[serde(default = "default_page_size")]
+	limit: usize,
+	/// The `next` value of the previous page
+	after: Option<String>
+}
+
+#[derive(Debug, Serialize)]
+pub struct Page<T> {
+	items: Vec<T>,
+	/// Pass as `after` to get the next page; absent on the last page
+	#[serde(skip_serializing_if = "Option::is_none")]
+	next: Option<String>
+}
+
+impl<T> Page<T> {
+	/// Sorts `items` by `key`, then takes up to `query.limit` of those that come after
+	/// `query.after`
+	fn new(mut items: Vec<T>, key: impl Fn(&T) -> &str, query: &PageQuery) -> Self {
+		items.sort_unstable_by(|a, b| key(a).cmp(key(b)));
+		let start = query.after.as_deref().map_or(0, |after| items.partition_point(|item| key(item) <= after));
+		let mut items = items.split_off(start);
+		let limit = query.limit.clamp(1, MAX_PAGE_SIZE);
+		let more = items.len() > limit;
+		items.truncate(limit);
+		let next = match more {
+			true => items.last().map(|item| key(item).to_owned()),
+			false => None
+		};
+		Self { items, next }
+	}
+}
+
+/// When an object was last written, and how long ago that was
+#[derive(Debug, Serialize)]
+pub struct Modified {
+	modified: String,
+	age_seconds: u64
+}
+
+impl From<SystemTime> for Modified {
+	fn from(modified: SystemTime) -> Self {
+		Self {
+			modified: humantime::format_rfc3339_seconds(modified).to_string(),
+			age_seconds: SystemTime::now().duration_since(modified).unwrap_or_default().as_secs()
+		}
+	}
+}
+
+/// Collects the objects directly under `prefix`, keyed by the rest of their path
+async fn list_children(config: &RequestConfig, prefix: &str) -> Result<Vec<(String, ObjectInfo)>, Error> {
+	let mut children = Vec::new();
+	let mut objects = config.repo.list(prefix);
+	while let Some(obj) = objects.try_next().await? {
+		match obj.key.strip_prefix(prefix) {
+			Some(name) if !name.contains('/') => children.push((name.to_owned(), obj)),
+			_ => ()
+		};
+	}
+	Ok(children)
+}
+
+pub async fn namespaces(query: web::Query<PageQuery>, config: web::Data<RequestConfig>) -> Result<web::Json<Page<String>>, Error> {
+	let mut namespaces = BTreeSet::new();
+	let mut manifests = config.repo.list("manifests/");
+	while let Some(obj) = manifests.try_next().await? {
+		if let Some(namespace) = storage::manifest_namespace(&obj.key) {
+			namespaces.insert(namespace.to_owned());
+		}
+	}
+	Ok(web::Json(Page::new(namespaces.into_iter().collect(), String::as_str, &query)))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RepositoriesRequest {
+	namespace: String
+}
+
+#[derive(Debug, Serialize)]
+pub struct CachedRepository {
+	name: String,
+	/// Number of cached manifests
+	manifests: usize
+}
+
+pub async fn repositories(req: web::Path<RepositoriesRequest>, query: web::Query<PageQuery>, config: web::Data<RequestConfig>) -> Result<web::Json<Page<CachedRepository>>, Error> {
+	let prefix = format!("manifests/{}/", req.namespace);
+	let mut repositories = BTreeMap::<String, usize>::new();
+	let mut manifests = config.repo.list(&prefix);
+	while let Some(obj) = manifests.try_next().await? {
+		if let Some((name, _)) = obj.key.strip_prefix(prefix.as_str()).and_then(|path| path.rsplit_once('/')) {
+			*repositories.entry(name.to_owned()).or_default() += 1;
+		}
+	}
+	let repositories = repositories.into_iter().map(|(name, manifests)| CachedRepository { name, manifests }).collect();
+	Ok(web::Json(Page::new(repositories, |r| r.name.as_str(), &query)))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ManifestsRequest {
+	image: String
+}
+
+#[derive(Debug, Serialize)]
+pub struct CachedManifest {
+	/// Tag or digest
+	reference: String,
+	size: u64,
+	#[serde(flatten)]
+	modified: Modified
+}
+
+pub async fn manifests(req: web::Path<ManifestsRequest>, qstr: web::Query<ManifestQueryString>, query: web::Query<PageQuery>, config: web::Data<RequestConfig>) -> Result<web::Json<Page<CachedManifest>>, Error> {
+	let (namespace, image) = split_image(qstr.ns.as_deref(), req.image.as_ref(), config.default_ns.as_ref());
+	let manifests = list_children(&config, &storage::manifest_path(namespace, image, ""))
+		.await?
+		.into_iter()
+		.map(|(reference, obj)| CachedManifest { reference, size: obj.size, modified: obj.modified.into() })
+		.collect();
+	Ok(web::Json(Page::new(manifests, |m| m.reference.as_str(), &query)))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BlobRequest {
+	digest: String
+}
+
+#[derive(Debug, Serialize)]
+pub struct Referrer {
+	namespace: String,
+	repository: String,
+	/// Tag or digest of a cached manifest in this repository that references the blob; absent
+	/// for repositories that pulled the blob but no longer have a cached manifest referencing it
+	#[serde(skip_serializing_if = "Option::is_none")]
+	reference: Option<String>
+}
+
+#[derive(Debug, Serialize)]
+pub struct CachedBlob {
+	digest: String,
+	size: u64,
+	#[serde(flatten)]
+	modified: Modified,
+	referenced_by: Vec<Referrer>
+}
+
+/// A blob's metadata, along with the repositories that have pulled it and the cached manifests in
+/// those repositories that reference it
+pub async fn blob(req: web::Path<BlobRequest>, config: web::Data<RequestConfig>) -> Result<web::Json<CachedBlob>, Error> {
+	let parsed = parse_digest(&req.digest)?;
+	let (digest, blob_path) = (parsed.to_string(), storage::blob_path(&parsed));
+	// Only the metadata is needed; dropping the stream without reading it is cheap
+	let obj = match config.repo.read_unrecorded(&blob_path).await {
+		Ok(v) => v,
+		Err(e) if e.is_not_found() => return Err(Error::NotFound),
+		Err(e) => return Err(e.into())
+	};
+
+	let mut referenced_by = Vec::new();
+	let mut refs = config.repo.list(&format!("refs/{blob_path}/"));
+	while let Some(marker) = refs.try_next().await? {
+		let Some((_, namespace, repository)) = storage::parse_reference_path(&marker.key) else {
+			continue;
+		};
+		let mut found = false;
+		for (reference, manifest) in list_children(&config, &storage::manifest_path(namespace, repository, "")).await? {
+			let manifest_refs = match References::read(&config.repo, &manifest.key).await {
+				Ok(Some(v)) => v,
+				Ok(None) | Err(storage::Error::InvalidManifest(..)) => continue,
+				Err(e) => return Err(e.into())
+			};
+			if (manifest_refs.blobs().any(|d| d == digest)) {
+				referenced_by.push(Referrer {
+					namespace: namespace.to_owned(),
+					repository: repository.to_owned(),
+					reference: Some(reference)
+				});
+				found = true;
+			}
+		}
+		if (!found) {
+			referenced_by.push(Referrer {
+				namespace: namespace.to_owned(),
+				repository: repository.to_owned(),
+				reference: None
+			});
+		}
+	}
+
+	Ok(web::Json(CachedBlob {
+		digest,
+		size: obj.length(),
+		modified: obj.modified().into(),
+		referenced_by
+	}))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PurgeQuery {
+	/// Also delete blobs that are no longer referenced by any cached manifest once the purged
+	/// manifests are gone
+	#[serde(default)]
+	cascade: bool
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TagQuery {
+	/// Glob matching the tags (or digests) to purge; all of them if absent
+	tag: Option<String>
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PurgeNamespaceRequest {
+	namespace: String
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PurgeRepositoryRequest {
+	namespace: String,
+	repository: String
+}
+
+/// Rejects anything that, built into a storage path, would name some other directory than it
+/// appears to, e.g. `..`, which would purge the whole cache
+fn check_path(path: String) -> Result<String, Error> {
+	match (path.starts_with('/') || path.split('/').any(|segment| matches!(segment, "" | "." | ".."))) {
+		true => Err(Error::InvalidPath(path)),
+		false => Ok(path)
+	}
+}
+
+pub async fn purge_namespace(req: web::Path<PurgeNamespaceRequest>, query: web::Query<PurgeQuery>, config: web::Data<RequestConfig>) -> Result<HttpResponse, Error> {
+	let namespace = check_path(req.into_inner().namespace)?;
+	Ok(start_purge(config, Target::Namespace(namespace), query.cascade))
+}
+
+pub async fn purge_repository(req: web::Path<PurgeRepositoryRequest>, query: web::Query<PurgeQuery>, tags: web::Query<TagQuery>, config: web::Data<RequestConfig>) -> Result<HttpResponse, Error> {
+	let req = req.into_inner();
+	let target = Target::Repository {
+		namespace: check_path(req.namespace)?,
+		repository: check_path(req.repository)?,
+		tags: tags.into_inner().tag
+	};
+	Ok(start_purge(config, target, query.cascade))
+}
+
+/// Purges `target` in the background, responding with where to find the job's status
+fn start_purge(config: web::Data<RequestConfig>, target: Target, cascade: bool) -> HttpResponse {
+	let job = config.jobs.start(target.to_string());
+	let id = job.id();
+	rt::spawn(async move {
+		let result = async {
+			let platforms = config.invalidation_config().await.platforms;
+			// If we can't tell what's pinned, it isn't safe to delete any blobs
+			let pinned = match cascade {
+				true => Some(config.pins.protected(&config.repo, &platforms).await?),
+				false => None
+			};
+			let cascade = pinned.as_ref().map(|pinned| Cascade { pinned, platforms: &platforms });
+			purge::purge(&config.repo, &target, cascade, &job).await
+		}
+		.await;
+		if let Err(error) = result.as_ref() {
+			error!(%target, %error, "Error purging");
+		}
+		job.finish(result);
+	});
+	accepted(id)
+}
+
+/// Runs a cleanup pass now, unless one is already running here or on another replica
+pub async fn cleanup(options: web::Query<cleanup::Options>, config: web::Data<RequestConfig>) -> Result<HttpResponse, Error> {
+	let lock = config.cleaner.lock().await?;
+	let options = options.into_inner();
+	let upstream = config.invalidation_config().await;
+	let job = config.jobs.start(match options.dry_run {
+		true => "cleanup (dry run)",
+		false => "cleanup"
+	});
+	let id = job.id();
+	rt::spawn(async move { lock.run(&upstream, options, job).await });
+	Ok(accepted(id))
+}
+
+/// The last cleanup pass that finished, on whichever replica ran it
+pub async fn last_cleanup(config: web::Data<RequestConfig>) -> Result<HttpResponse, Error> {
+	let body = config.cleaner.last_pass().await?.ok_or(Error::NotFound)?;
+	Ok(HttpResponse::Ok().content_type("application/json").body(body))
+}
+
+/// Responds that job `id` has been started, and where to find its status
+fn accepted(id: u64) -> HttpResponse {
+	HttpResponse::Accepted()
+		.insert_header((http::header::LOCATION, format!("/_admin/jobs/{id}")))
+		.json(serde_json::json!({ "id": id }))
+}
+
+/// Running jobs, followed by those that have finished, most recent first
+pub async fn jobs(config: web::Data<RequestConfig>) -> web::Json<Vec<Job>> {
+	web::Json(config.jobs.list())
+}
+
+#[derive(Debug, Deserialize)]
+pub struct JobRequest {
+	id: u64
+}
+
+pub async fn job(req: web::Path<JobRequest>, config: web::Data<RequestConfig>) -> Result<web::Json<Job>, Error> {
+	config.jobs.get(req.id).map(web::Json).ok_or(Error::NotFound)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn paginate() {
+		let items = || vec!["c".to_owned(), "a".to_owned(), "d".to_owned(), "b".to_owned()];
+		let page = Page::new(items(), String::as_str, &PageQuery { limit: 3, after: None });
+		assert_eq!(page.items, vec!["a", "b", "c"]);
+		assert_eq!(page.next.as_deref(), Some("c"));
+		let page = Page::new(items(), String::as_str, &PageQuery { limit: 3, after: page.next });
+		assert_eq!(page.items, vec!["d"]);
+		assert_eq!(page.next, None);
+	}
+
+	#[test]
+	fn purge_paths() {
+		for valid in ["docker.io", "library/busybox", "a.b/c..d"] {
+			assert_eq!(check_path(valid.to_owned()).unwrap(), valid);
+		}
+		for invalid in ["", ".", "..", "/etc", "library/../..", "library//busybox", "library/"] {
+			assert!(matches!(check_path(invalid.to_owned()), Err(Error::InvalidPath(_))), "{invalid}");
+		}
+	}
+}
@@ -9,6 +9,7 @@ use rusoto_s3::GetObjectError;
 use tracing::error;
 
 use crate::api::stream::DigestMismatchError;
+use crate::cleanup::Error as Cleanup;
 use crate::storage::Error as Storage;
 
 #[derive(Debug, thiserror::Error)]
@@ -26,7 +27,28 @@ pub enum Error {
 	#[error("JSON error: {0}")]
 	Json(#[from] serde_json::Error),
 	#[error("{0}")]
-	DataCorrupt(#[from] DigestMismatchError)
+	DataCorrupt(#[from] DigestMismatchError),
+	#[error("Pinned in configuration; it can only be unpinned there")]
+	ConfiguredPin,
+	#[error("Manifest from upstream doesn't match the requested digest: {0}")]
+	ManifestDigestMismatch(DigestMismatchError),
+	#[error("Not found")]
+	NotFound,
+	#[error("{0}")]
+	Cleanup(#[from] Cleanup),
+	#[error("Invalid path: {0}")]
+	InvalidPath(String)
+}
+
+impl Error {
+	/// The status upstream answered with, if this is an unexpected answer from upstream
+	pub fn upstream_status(&self) -> Option<StatusCode> {
+		match self {
+			Self::Upstream(Upstream::UnexpectedHttpStatus(status)) => Some(*status),
+			Self::Upstream(Upstream::Client { status }) => Some(*status),
+			_ => None
+		}
+	}
 }
 
 impl actix_web::ResponseError for Error {
@@ -49,14 +71,28 @@ impl actix_web::ResponseError for Error {
 			Self::MissingContentLength => StatusCode::INTERNAL_SERVER_ERROR,
 			Self::Io(_) => StatusCode::INTERNAL_SERVER_ERROR,
 			Self::Json(_) => StatusCode::INTERNAL_SERVER_ERROR,
-			Self::DataCorrupt(_) => StatusCode::INTERNAL_SERVER_ERROR
+			Self::DataCorrupt(_) => StatusCode::INTERNAL_SERVER_ERROR,
+			Self::ConfiguredPin => StatusCode::CONFLICT,
+			Self::ManifestDigestMismatch(_) => StatusCode::BAD_GATEWAY,
+			Self::NotFound => StatusCode::NOT_FOUND,
+			Self::Cleanup(e) => match e {
+				Cleanup::Running | Cleanup::Locked(_) | Cleanup::LeaseLost => StatusCode::CONFLICT,
+				Cleanup::Storage(_) => StatusCode::INTERNAL_SERVER_ERROR
+			},
+			Self::InvalidPath(_) => StatusCode::BAD_REQUEST
 		}
 	}
 
 	fn error_response(&self) -> HttpResponse<BoxBody> {
 		let status_code = self.status_code();
 		error!("{}: {}", status_code.as_u16(), self);
-		HttpResponseBuilder::new(status_code).body(self.to_string())
+		match self {
+			// Clients can act on this one, so it's reported the way the distribution spec says to
+			Self::ManifestDigestMismatch(_) => HttpResponseBuilder::new(status_code).json(serde_json::json!({
+				"errors": [{"code": "DIGEST_INVALID", "message": "provided digest did not match uploaded content", "detail": self.to_string()}]
+			})),
+			_ => HttpResponseBuilder::new(status_code).body(self.to_string())
+		}
 	}
 }
 
@@ -8,8 +8,10 @@ use futures::stream::StreamExt;
 use futures::task::Context;
 use futures::task::Poll;
 use pin_project::pin_project;
-use sha2::Digest;
-use sha2::Sha256;
+
+use crate::digest::Algorithm;
+use crate::digest::Digest;
+use crate::digest::Hasher;
 
 #[pin_project]
 pub struct DigestCheckedStream<S, E, IE>
@@ -19,8 +21,8 @@ where
 {
 	#[pin]
 	inner: S,
-	wanted_digest: [u8; 32],
-	hasher: Option<Sha256>,
+	wanted_digest: Digest,
+	hasher: Option<Hasher>,
 	_e: PhantomData<E>
 }
 
@@ -45,11 +47,11 @@ where
 			return match std::mem::take(&mut self.hasher) {
 				None => Poll::Ready(None),
 				Some(hasher) => {
-					let result: [u8; 32] = hasher.finalize().into();
+					let result = hasher.finalize();
 					match (result == self.wanted_digest) {
 						true => Poll::Ready(None),
 						false => {
-							let error = DigestMismatchError { expected: self.wanted_digest, actual: result };
+							let error = DigestMismatchError { expected: self.wanted_digest.clone(), actual: result };
 							Poll::Ready(Some(Err(error.into())))
 						}
 					}
@@ -71,11 +73,11 @@ where
 	S: Stream<Item = Result<Bytes, IE>> + Unpin,
 	E: std::error::Error + From<IE> + From<DigestMismatchError> + 'static
 {
-	pub fn new(inner: S, wanted_digest: [u8; 32]) -> Self {
+	pub fn new(inner: S, wanted_digest: Digest) -> Self {
 		Self {
 			inner,
+			hasher: Some(wanted_digest.algorithm().hasher()),
 			wanted_digest,
-			hasher: Some(Sha256::new()),
 			_e: PhantomData
 		}
 	}
@@ -83,35 +85,33 @@ where
 
 #[derive(Debug, Clone)]
 pub struct DigestMismatchError {
-	expected: [u8; 32],
-	actual: [u8; 32]
+	expected: Digest,
+	actual: Digest
+}
+
+impl DigestMismatchError {
+	pub fn new(expected: Digest, actual: Digest) -> Self {
+		Self { expected, actual }
+	}
 }
 
 impl fmt::Display for DigestMismatchError {
 	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-		f.write_str("Digest '")?;
-		for byte in self.actual.iter() {
-			write!(f, "{:x}", byte)?;
-		}
-		f.write_str("' did not match expected '")?;
-		for byte in self.expected.iter() {
-			write!(f, "{:x}", byte)?;
-		}
-		f.write_str("'")
+		write!(f, "Digest '{}' did not match expected '{}'", self.actual, self.expected)
 	}
 }
 
 impl std::error::Error for DigestMismatchError {}
 
-pub async fn hash<S, E>(mut stream: S) -> Result<[u8; 32], E>
+pub async fn hash<S, E>(mut stream: S, algorithm: Algorithm) -> Result<Digest, E>
 where
 	S: Stream<Item = Result<Bytes, E>> + Unpin,
 	E: std::error::Error + 'static
 {
-	let mut hasher = Sha256::new();
+	let mut hasher = algorithm.hasher();
 	while let Some(chunk) = stream.next().await {
 		let chunk = chunk?;
 		hasher.update(&chunk);
 	}
-	Ok(hasher.finalize().into())
+	Ok(hasher.finalize())
 }
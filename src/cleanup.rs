@@ -0,0 +1,421 @@
+use core::iter;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use actix_web::rt;
+use bytes::Bytes;
+use bytes::BytesMut;
+use bytesize::ByteSize;
+use clap::Parser;
+use compact_str::CompactString;
+use futures::future::select;
+use futures::future::Either;
+use futures::stream::TryStreamExt;
+use humantime::Duration;
+use once_cell::sync::Lazy;
+use prometheus::register_int_counter;
+use prometheus::register_int_counter_vec;
+use prometheus::register_int_gauge_vec;
+use prometheus::IntCounter;
+use prometheus::IntCounterVec;
+use prometheus::IntGaugeVec;
+use serde::Deserialize;
+use serde::Serialize;
+use tokio::sync::Mutex;
+use tokio::sync::OwnedMutexGuard;
+use tokio::task::JoinHandle;
+use tracing::error;
+use tracing::info;
+use tracing::warn;
+
+use crate::gc;
+use crate::gc::GcConfig;
+use crate::jobs::Job;
+use crate::jobs::JobHandle;
+use crate::jobs::Jobs;
+use crate::pin::Pins;
+use crate::storage;
+use crate::storage::Eviction;
+use crate::storage::Origin;
+use crate::storage::Removed;
+use crate::storage::Repository;
+use crate::upstream::InvalidationConfig;
+
+/// Where the lease held by whichever replica is cleaning up is stored
+const LEASE_PATH: &str = "locks/cleanup";
+/// Where the outcome of the last pass, on whichever replica ran it, is stored
+const LAST_PASS_PATH: &str = "locks/cleanup-last-pass";
+/// How long a lease lasts unless renewed, e.g. if the replica holding it dies mid-pass
+const LEASE_DURATION: core::time::Duration = core::time::Duration::from_secs(600);
+/// How long to wait after taking the lease before checking that another replica didn't take it at
+/// the same time
+const LEASE_SETTLE: core::time::Duration = core::time::Duration::from_secs(2);
+
+#[derive(Clone, Debug, Parser)]
+pub struct CleanupConfig {
+	/// How often to age out, garbage collect, and evict cached objects
+	#[clap(env, long, default_value = "5m")]
+	cleanup_interval: Duration,
+	/// If set, the least-recently-accessed blobs will be evicted during each cleanup pass until
+	/// the total size of all cached blobs is under this size, in addition to normal aging
+	#[clap(env, long)]
+	max_cache_size: Option<ByteSize>,
+	#[clap(flatten)]
+	gc: GcConfig
+}
+
+impl CleanupConfig {
+	pub fn cleaner(self, repo: Repository, pins: Pins) -> Cleaner {
+		let owner = format!("{}/{}/{}", std::env::var("HOSTNAME").unwrap_or_default(), std::process::id(), unix_time(SystemTime::now()));
+		Cleaner {
+			config: self,
+			repo,
+			pins,
+			running: Default::default(),
+			owner: owner.into()
+		}
+	}
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+	#[error("A cleanup pass is already running")]
+	Running,
+	#[error("Another replica ({0}) is cleaning up")]
+	Locked(String),
+	#[error("Lost the cleanup lease to another replica")]
+	LeaseLost,
+	#[error("Error with storage subsystem: {0}")]
+	Storage(#[from] storage::Error)
+}
+
+/// What to do differently in an on-demand pass
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+pub struct Options {
+	/// Report what would be deleted without deleting anything
+	#[serde(default)]
+	pub dry_run: bool,
+	/// Collect garbage even if `--gc` isn't set
+	#[serde(default)]
+	pub gc: bool
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct Report {
+	dry_run: bool,
+	/// Manifests aged out, by namespace
+	manifests: BTreeMap<CompactString, Removed>,
+	/// Blobs aged out, by the namespace whose invalidation time they outlived; see
+	/// [`Repository::delete_old_blobs`]
+	blobs: BTreeMap<CompactString, Removed>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	gc: Option<gc::Report>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	evicted: Option<Eviction>,
+	/// Errors that didn't stop the rest of the pass
+	errors: Vec<String>
+}
+
+/// Ages out, garbage collects, and evicts cached objects, making sure that only one pass runs at a
+/// time, even across replicas sharing storage
+#[derive(Clone)]
+pub struct Cleaner {
+	config: CleanupConfig,
+	repo: Repository,
+	pins: Pins,
+	running: Arc<Mutex<()>>,
+	/// Identifies this process in the lease
+	owner: Arc<str>
+}
+
+impl Cleaner {
+	pub fn interval(&self) -> core::time::Duration {
+		*self.config.cleanup_interval
+	}
+
+	/// Takes the lock that keeps passes from overlapping, both within this process and, by way of
+	/// a lease stored alongside the cache, with other replicas.  The lease is best-effort:  storage
+	/// offers no compare-and-swap, so it's written, then read back after a moment to see whether
+	/// another replica's write won.
+	pub async fn lock(&self) -> Result<Lock, Error> {
+		let running = self.running.clone().try_lock_owned().map_err(|_| Error::Running)?;
+		if let Some(lease) = read_lease(&self.repo).await? {
+			if (lease.owner != *self.owner && lease.expires > unix_time(SystemTime::now())) {
+				return Err(Error::Locked(lease.owner));
+			}
+		}
+		write_lease(&self.repo, &self.owner).await?;
+		tokio::time::sleep(LEASE_SETTLE).await;
+		match read_lease(&self.repo).await? {
+			Some(lease) if lease.owner == *self.owner => (),
+			Some(lease) => return Err(Error::Locked(lease.owner)),
+			None => return Err(Error::Locked("unknown".to_owned()))
+		};
+
+		let renewal = {
+			let (repo, owner) = (self.repo.clone(), self.owner.clone());
+			rt::spawn(async move {
+				// Returns only once the lease is lost, which ends the pass
+				loop {
+					tokio::time::sleep(LEASE_DURATION / 3).await;
+					match read_lease(&repo).await {
+						Ok(Some(lease)) if lease.owner == *owner => (),
+						Ok(_) => {
+							warn!("Lost the cleanup lease to another replica");
+							return;
+						},
+						Err(error) => {
+							error!(%error, "Error reading cleanup lease");
+							continue;
+						}
+					};
+					if let Err(error) = write_lease(&repo, &owner).await {
+						error!(%error, "Error renewing cleanup lease");
+					}
+				}
+			})
+		};
+		Ok(Lock { cleaner: self.clone(), _running: running, renewal })
+	}
+
+	/// The outcome of the last pass that finished, on whichever replica ran it, as JSON
+	pub async fn last_pass(&self) -> Result<Option<Bytes>, storage::Error> {
+		let stream = match self.repo.read_shared(LAST_PASS_PATH).await {
+			Ok(v) => v,
+			Err(e) if e.is_not_found() => return Ok(None),
+			Err(e) => return Err(e)
+		};
+		Ok(Some(stream.into_inner().try_collect::<BytesMut>().await?.freeze()))
+	}
+
+	async fn save_last_pass(&self, job: &Job) -> Result<(), storage::Error> {
+		write_json(&self.repo, LAST_PASS_PATH, &LastPass { owner: &self.owner, job }).await
+	}
+
+	/// Runs a pass as a job unless one is already running here or on another replica
+	pub async fn run_scheduled(&self, upstream: &InvalidationConfig, jobs: &Jobs) {
+		// Every replica does this, so that eviction sees what each of them has served
+		if let Err(error) = self.repo.save_access_times().await {
+			error!(%error, "Error saving blob access times");
+		}
+		match self.lock().await {
+			Ok(lock) => lock.run(upstream, Options::default(), jobs.start("cleanup")).await,
+			Err(error @ (Error::Running | Error::Locked(_))) => info!(%error, "Skipping cleanup"),
+			Err(error) => error!(%error, "Error taking cleanup lock; skipping cleanup")
+		};
+	}
+}
+
+/// Held for the duration of a pass
+pub struct Lock {
+	cleaner: Cleaner,
+	_running: OwnedMutexGuard<()>,
+	renewal: JoinHandle<()>
+}
+
+impl Lock {
+	/// Runs a pass, reporting its results through `job` and to other replicas, then releases the
+	/// lock.  If the lease is lost partway through, the rest of the pass is abandoned, since
+	/// another replica may be cleaning up by then.
+	pub async fn run(mut self, upstream: &InvalidationConfig, options: Options, job: JobHandle) {
+		let result = match select(Box::pin(self.cleaner.pass(upstream, options)), &mut self.renewal).await {
+			Either::Left((result, _)) => result.map_err(Error::from),
+			Either::Right(_) => Err(Error::LeaseLost)
+		};
+		self.renewal.abort();
+		match read_lease(&self.cleaner.repo).await {
+			Ok(Some(lease)) if lease.owner == *self.cleaner.owner => {
+				if let Err(error) = self.cleaner.repo.delete(LEASE_PATH).await {
+					error!(%error, "Error releasing cleanup lease");
+				}
+			},
+			Ok(_) => (),
+			Err(error) => error!(%error, "Error reading cleanup lease")
+		};
+		let job = match result {
+			Ok(report) => {
+				job.report(&report);
+				job.finish(Ok::<_, Error>(()))
+			},
+			Err(error) => {
+				error!(%error, "Error cleaning up");
+				job.finish(Err(error))
+			}
+		};
+		if let Some(job) = job {
+			if let Err(error) = self.cleaner.save_last_pass(&job).await {
+				error!(%error, "Error saving cleanup report");
+			}
+		}
+	}
+}
+
+impl Cleaner {
+	async fn pass(&self, upstream: &InvalidationConfig, options: Options) -> Result<Report, storage::Error> {
+		static EVICTED_COUNT: Lazy<IntCounter> = Lazy::new(|| register_int_counter!("cache_evicted_blobs", "Number of blobs evicted to stay under the maximum cache size").unwrap());
+		static EVICTED_BYTES: Lazy<IntCounter> = Lazy::new(|| register_int_counter!("cache_evicted_bytes", "Total size of blobs evicted to stay under the maximum cache size").unwrap());
+
+		let Cleaner { config, repo, pins, .. } = self;
+		let dry_run = options.dry_run;
+		let mut report = Report { dry_run, ..Default::default() };
+		// If we can't tell what's pinned, it isn't safe to delete anything
+		let pinned = pins.protected(repo, &upstream.platforms).await?;
+
+		// What's cached, as counted by the walks that age things out; None if a walk failed
+		let (mut blobs, mut manifests) = (None, Some(Removed::default()));
+		let now = SystemTime::now();
+		match repo.delete_old_blobs(now, upstream.blob, &upstream.blobs, &pinned, dry_run).await {
+			Ok((removed, remaining)) => {
+				report.blobs.extend(removed);
+				blobs = Some(remaining);
+			},
+			Err(error) => {
+				error!(%error, "Error cleaning up blobs");
+				report.errors.push(format!("Error cleaning up blobs: {error}"));
+			}
+		};
+		for (ns, age) in upstream.manifests.iter() {
+			match repo.delete_old_manifests(ns, now - *age, &pinned, dry_run).await {
+				Ok((removed, remaining)) => {
+					if (removed.count > 0) {
+						report.manifests.insert(ns.clone(), removed);
+					}
+					if let Some(manifests) = manifests.as_mut() {
+						manifests.add_all(remaining);
+					}
+				},
+				Err(error) => {
+					manifests = None;
+					error!(%error, namespace = ns.as_str(), "Error cleaning up manifests");
+					report.errors.push(format!("Error cleaning up manifests in {ns}: {error}"));
+				}
+			};
+		}
+
+		let count = report.blobs.values().chain(report.manifests.values()).map(|removed| removed.count).sum::<usize>();
+		if (count > 0 && !dry_run) {
+			warn!(count, "Aged out objects");
+		} else {
+			info!(count, dry_run, "Aged out objects");
+		}
+
+		match config.gc.run(repo, &pinned, &upstream.platforms, options.gc, dry_run).await {
+			Ok(v) => report.gc = v,
+			Err(error) => {
+				error!(%error, "Error collecting garbage");
+				report.errors.push(format!("Error collecting garbage: {error}"));
+			}
+		};
+
+		if (!dry_run) {
+			count_deleted("manifest", &report.manifests);
+			count_deleted("blob", &report.blobs);
+		}
+
+		if let Some(max_cache_size) = config.max_cache_size {
+			match repo.evict_blobs(max_cache_size.as_u64(), &pinned, dry_run).await {
+				Ok(eviction) => {
+					if (!dry_run) {
+						EVICTED_COUNT.inc_by(eviction.count.try_into().unwrap_or_default());
+						EVICTED_BYTES.inc_by(eviction.bytes);
+						if let Some(blobs) = blobs.as_mut() {
+							blobs.count = blobs.count.saturating_sub(eviction.count);
+							blobs.bytes = blobs.bytes.saturating_sub(eviction.bytes);
+						}
+					}
+					info!(count = eviction.count, bytes = eviction.bytes, remaining = eviction.remaining, dry_run, "Evicted blobs");
+					report.evicted = Some(eviction);
+				},
+				Err(error) => {
+					error!(%error, "Error evicting blobs");
+					report.errors.push(format!("Error evicting blobs: {error}"));
+				}
+			};
+		}
+
+		for (kind, cached) in [("manifest", manifests), ("blob", blobs)] {
+			if let Some(cached) = cached {
+				record_size(kind, cached);
+			}
+		}
+		Ok(report)
+	}
+}
+
+fn count_deleted(kind: &str, removed: &BTreeMap<CompactString, Removed>) {
+	static OBJECTS: Lazy<IntCounterVec> = Lazy::new(|| register_int_counter_vec!("cleanup_deleted_objects", "Number of objects aged out by cleanup", &["namespace", "kind"]).unwrap());
+	static BYTES: Lazy<IntCounterVec> = Lazy::new(|| register_int_counter_vec!("cleanup_deleted_bytes", "Total size of objects aged out by cleanup", &["namespace", "kind"]).unwrap());
+
+	for (namespace, removed) in removed {
+		OBJECTS.with_label_values(&[namespace.as_str(), kind]).inc_by(removed.count.try_into().unwrap_or_default());
+		BYTES.with_label_values(&[namespace.as_str(), kind]).inc_by(removed.bytes);
+	}
+}
+
+/// Updates the gauges of how much is cached.  Manifests are only counted in namespaces that
+/// they're aged out of, i.e. configured ones.
+fn record_size(kind: &str, cached: Removed) {
+	static OBJECTS: Lazy<IntGaugeVec> = Lazy::new(|| register_int_gauge_vec!("cache_objects", "Number of cached objects as of the last cleanup pass", &["kind"]).unwrap());
+	static BYTES: Lazy<IntGaugeVec> = Lazy::new(|| register_int_gauge_vec!("cache_bytes", "Total size of cached objects as of the last cleanup pass", &["kind"]).unwrap());
+
+	OBJECTS.with_label_values(&[kind]).set(cached.count.try_into().unwrap_or(i64::MAX));
+	BYTES.with_label_values(&[kind]).set(cached.bytes.try_into().unwrap_or(i64::MAX));
+}
+
+impl Drop for Lock {
+	fn drop(&mut self) {
+		self.renewal.abort();
+	}
+}
+
+/// A pass as it's reported by `GET /_admin/cleanup`
+#[derive(Debug, Serialize)]
+struct LastPass<'a> {
+	/// Which replica ran it
+	owner: &'a str,
+	#[serde(flatten)]
+	job: &'a Job
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct Lease {
+	owner: String,
+	/// Seconds since the Unix epoch
+	expires: u64
+}
+
+async fn read_lease(repo: &Repository) -> Result<Option<Lease>, storage::Error> {
+	let stream = match repo.read_shared(LEASE_PATH).await {
+		Ok(v) => v,
+		Err(e) if e.is_not_found() => return Ok(None),
+		Err(e) => return Err(e)
+	};
+	let body = stream.into_inner().try_collect::<BytesMut>().await?;
+	match serde_json::from_slice(body.as_ref()) {
+		Ok(v) => Ok(Some(v)),
+		Err(error) => {
+			warn!(%error, "Cleanup lease is corrupt; ignoring it");
+			Ok(None)
+		}
+	}
+}
+
+async fn write_lease(repo: &Repository, owner: &str) -> Result<(), storage::Error> {
+	let lease = Lease {
+		owner: owner.to_owned(),
+		expires: unix_time(SystemTime::now() + LEASE_DURATION)
+	};
+	write_json(repo, LEASE_PATH, &lease).await
+}
+
+async fn write_json<T: Serialize>(repo: &Repository, path: &str, value: &T) -> Result<(), storage::Error> {
+	let body = Bytes::from(serde_json::to_vec(value).unwrap());
+	let len = body.len().try_into().unwrap_or(i64::MAX);
+	repo.write(path, futures::stream::iter(iter::once(Result::<_, std::io::Error>::Ok(body))), len, &Origin::new("", ""))
+		.await
+}
+
+fn unix_time(time: SystemTime) -> u64 {
+	time.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_secs()
+}
@@ -0,0 +1,302 @@
+use core::fmt;
+use std::ffi::OsString;
+
+use camino::Utf8PathBuf;
+use clap::error::ContextKind;
+use clap::error::ContextValue;
+use clap::error::ErrorKind;
+use clap::Arg;
+use clap::Command;
+use clap::Parser;
+use serde::Deserialize;
+use serde_yaml::Mapping;
+use serde_yaml::Value;
+
+use crate::upstream::SingleUpstreamConfig;
+
+const STORAGE_MODES: [&str; 3] = ["s3", "filesystem", "tiered"];
+
+/// Something wrong with the configuration, and where it is, if it's in the config file
+#[derive(Debug)]
+pub struct Problem {
+	path: Option<Utf8PathBuf>,
+	line: Option<usize>,
+	message: String
+}
+
+impl fmt::Display for Problem {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match (&self.path, self.line) {
+			(Some(path), Some(line)) => write!(f, "{path}:{line}: {}", self.message),
+			(Some(path), None) => write!(f, "{path}: {}", self.message),
+			(None, _) => f.write_str(&self.message)
+		}
+	}
+}
+
+impl From<String> for Problem {
+	fn from(message: String) -> Self {
+		Self { path: None, line: None, message }
+	}
+}
+
+/// A setting taken from the config file
+#[derive(Debug)]
+struct Setting {
+	long: String,
+	env: OsString,
+	line: Option<usize>
+}
+
+#[derive(Debug, Deserialize)]
+struct Document {
+	#[serde(default)]
+	storage: Option<Mapping>,
+	#[serde(default)]
+	upstreams: Option<Value>,
+	#[serde(flatten)]
+	settings: Mapping
+}
+
+/// Deserialized from the whole file on its own, so that errors in it are reported with the line
+/// they're on
+#[derive(Debug, Deserialize)]
+struct Upstreams {
+	#[serde(default)]
+	upstreams: Option<Vec<SingleUpstreamConfig>>
+}
+
+/// The file given with `--config-file`.  Each top-level key is the name of a command-line option,
+/// with underscores rather than dashes; `storage` holds the options of the storage backend, along
+/// with `mode`, the name of the backend; and `upstreams` is the list that would otherwise be in
+/// `--upstream-config-file`.
+#[derive(Debug)]
+pub struct ConfigFile {
+	path: Utf8PathBuf,
+	storage_mode: Option<String>,
+	/// Only those that weren't already set in the environment, which take precedence
+	settings: Vec<Setting>,
+	upstreams: Option<Vec<SingleUpstreamConfig>>
+}
+
+impl ConfigFile {
+	/// Reads the file given with `--config-file`, if any, and applies its settings by setting the
+	/// environment variables for them that aren't already set, so that both the environment and
+	/// the command line override it.  They're only set until [`parse`] is done with them, so that
+	/// they aren't passed on to child processes, e.g. credential helpers.  Settings are checked
+	/// against `command`; every problem found is returned.
+	pub fn load(command: &Command) -> Result<Option<Self>, Vec<Problem>> {
+		let Some(path) = path() else {
+			return Ok(None);
+		};
+		let problem = |line, message| Problem { path: Some(path.clone()), line, message };
+		let text = std::fs::read_to_string(&path).map_err(|e| vec![problem(None, e.to_string())])?;
+		let document: Document = serde_yaml::from_str(&text).map_err(|e| vec![problem(e.location().map(|l| l.line()), e.to_string())])?;
+
+		let mut problems = Vec::new();
+		// Each setting's option, its name as it's reported, its value, and the line it's on
+		let mut entries = Vec::new();
+		for (key, value) in document.settings.iter() {
+			let Some(key) = key.as_str() else {
+				problems.push(problem(None, format!("Setting names must be strings, not {key:?}")));
+				continue;
+			};
+			entries.push((command.get_arguments().find(|arg| arg.get_id() == key), key.to_owned(), value, find_line(&text, None, key)));
+		}
+
+		let mut storage_mode = None;
+		if let Some(storage) = &document.storage {
+			match storage.get("mode").and_then(Value::as_str) {
+				Some(mode) if STORAGE_MODES.contains(&mode) => {
+					let subcommand = command.find_subcommand(mode).unwrap();
+					for (key, value) in storage.iter() {
+						let Some(key) = key.as_str().filter(|key| *key != "mode") else {
+							continue;
+						};
+						entries.push((subcommand.get_arguments().find(|arg| arg.get_id() == key), format!("storage.{key}"), value, find_line(&text, Some("storage"), key)));
+					}
+					storage_mode = Some(mode.to_owned());
+				},
+				_ => problems.push(problem(find_line(&text, None, "storage"), format!("storage.mode must be one of {}", STORAGE_MODES.join(", "))))
+			};
+		}
+
+		let mut settings = Vec::new();
+		for (arg, key, value, line) in entries {
+			let Some(arg) = arg.filter(|arg| arg.get_id() != "config_file") else {
+				problems.push(problem(line, format!("Unknown setting `{key}`")));
+				continue;
+			};
+			match setting(arg, value) {
+				Ok(Some((long, env, value))) => {
+					if (std::env::var_os(env).is_none()) {
+						std::env::set_var(env, value);
+						settings.push(Setting { long: long.to_owned(), env: env.to_owned(), line });
+					}
+				},
+				Ok(None) => (),
+				Err(message) => problems.push(problem(line, format!("{key}: {message}")))
+			};
+		}
+
+		let upstreams = match document.upstreams {
+			Some(_) => match serde_yaml::from_str::<Upstreams>(&text) {
+				Ok(v) => v.upstreams,
+				Err(e) => {
+					problems.push(problem(e.location().map(|l| l.line()), e.to_string()));
+					None
+				}
+			},
+			None => None
+		};
+
+		match problems.is_empty() {
+			true => Ok(Some(Self { path, storage_mode, settings, upstreams })),
+			false => Err(problems)
+		}
+	}
+
+	pub fn upstreams(&mut self) -> Option<Vec<SingleUpstreamConfig>> {
+		self.upstreams.take()
+	}
+
+	/// The setting from this file that `error` is about, if any
+	fn setting(&self, error: &clap::Error) -> Option<&Setting> {
+		let ContextValue::String(arg) = error.get(ContextKind::InvalidArg)? else {
+			return None;
+		};
+		let long = arg.strip_prefix("--")?.split([' ', '=']).next()?;
+		self.settings.iter().find(|setting| setting.long == long)
+	}
+}
+
+/// Parses the command line into `T`, falling back to the environment, including settings applied
+/// from `file`, which are removed from the environment afterwards.  If the command line doesn't
+/// name a storage backend, the one in `file` is used.  Problems with settings from `file` are all
+/// reported, rather than just the first.
+pub fn parse<T: Parser>(file: Option<&ConfigFile>) -> Result<T, Vec<Problem>> {
+	let result = try_parse(file);
+	for setting in file.iter().flat_map(|file| file.settings.iter()) {
+		std::env::remove_var(&setting.env);
+	}
+	result
+}
+
+fn try_parse<T: Parser>(file: Option<&ConfigFile>) -> Result<T, Vec<Problem>> {
+	let mut args: Vec<OsString> = std::env::args_os().collect();
+	let mut storage_mode = file.and_then(|file| file.storage_mode.as_deref());
+	let mut problems = Vec::new();
+	loop {
+		let error = match T::try_parse_from(&args) {
+			Ok(v) if problems.is_empty() => return Ok(v),
+			Ok(_) => return Err(problems),
+			Err(e) => e
+		};
+		match error.kind() {
+			ErrorKind::DisplayHelp | ErrorKind::DisplayVersion => error.exit(),
+			ErrorKind::MissingSubcommand | ErrorKind::DisplayHelpOnMissingArgumentOrSubcommand if storage_mode.is_some() => {
+				args.push(storage_mode.take().unwrap().into());
+				continue;
+			},
+			_ => ()
+		};
+		let message = error.to_string();
+		let message = message.lines().next().unwrap_or_default().trim_start_matches("error: ").to_owned();
+		match file.and_then(|file| Some((file, file.setting(&error)?))) {
+			// Drop the setting and try again, to find any other problems
+			Some((file, setting)) if std::env::var_os(&setting.env).is_some() => {
+				std::env::remove_var(&setting.env);
+				problems.push(Problem { path: Some(file.path.clone()), line: setting.line, message });
+			},
+			// Most likely complaining that a setting dropped above is required
+			Some(_) if !problems.is_empty() => return Err(problems),
+			_ => {
+				problems.push(message.into());
+				return Err(problems);
+			}
+		};
+	}
+}
+
+/// `--config-file`, found before the command line is parsed, so that its settings can be applied
+/// first
+fn path() -> Option<Utf8PathBuf> {
+	let mut args = std::env::args_os().skip(1);
+	while let Some(arg) = args.next() {
+		let Some(arg) = arg.to_str() else {
+			continue;
+		};
+		if (arg == "--config-file") {
+			return args.next().and_then(|v| v.into_string().ok()).map(Into::into);
+		} else if let Some(path) = arg.strip_prefix("--config-file=") {
+			return Some(path.into());
+		}
+	}
+	std::env::var("CONFIG_FILE").ok().map(Into::into)
+}
+
+/// The long option and environment variable for `arg` and the value to set, as it would be given
+/// on the command line, or `None` if it's null
+fn setting<'a>(arg: &'a Arg, value: &Value) -> Result<Option<(&'a str, &'a std::ffi::OsStr, String)>, String> {
+	let (Some(long), Some(env)) = (arg.get_long(), arg.get_env()) else {
+		return Err("can't be set in the config file".into());
+	};
+	let value = match value {
+		Value::Null => return Ok(None),
+		Value::Sequence(values) => match arg.get_value_delimiter() {
+			Some(delimiter) => values.iter().map(scalar).collect::<Result<Vec<_>, _>>()?.join(&delimiter.to_string()),
+			None => return Err("expected a single value, not a list".into())
+		},
+		value => scalar(value)?
+	};
+	Ok(Some((long, env, value)))
+}
+
+fn scalar(value: &Value) -> Result<String, String> {
+	match value {
+		Value::Bool(v) => Ok(v.to_string()),
+		Value::Number(v) => Ok(v.to_string()),
+		Value::String(v) => Ok(v.clone()),
+		_ => Err("expected a string, number, or boolean".into())
+	}
+}
+
+/// The line `key` is on, at the top level or within the top-level `parent` mapping.  Found by a
+/// simple scan, since the YAML parser doesn't keep track of where values came from.
+fn find_line(text: &str, parent: Option<&str>, key: &str) -> Option<usize> {
+	let is_key = |line: &str, key: &str| line.strip_prefix(key).map_or(false, |rest| rest.trim_start().starts_with(':'));
+	let mut lines = text.lines().enumerate().map(|(i, line)| (i + 1, line));
+	let Some(parent) = parent else {
+		return lines.find(|(_, line)| is_key(line, key)).map(|(n, _)| n);
+	};
+	lines.find(|(_, line)| is_key(line, parent))?;
+	lines
+		.take_while(|(_, line)| line.is_empty() || line.starts_with([' ', '\t', '#']))
+		.find(|(_, line)| is_key(line.trim_start(), key))
+		.map(|(n, _)| n)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn lines() {
+		let text = "listen: 0.0.0.0:80\nstorage:\n  mode: s3\n  # comment\n  bucket: cache\ngc: true\nbucket: nope\n";
+		assert_eq!(find_line(text, None, "listen"), Some(1));
+		assert_eq!(find_line(text, None, "gc"), Some(6));
+		assert_eq!(find_line(text, Some("storage"), "bucket"), Some(5));
+		assert_eq!(find_line(text, Some("storage"), "gc"), None);
+	}
+
+	#[test]
+	fn values() {
+		let pins = Arg::new("pins").long("pin").env("PINS").value_delimiter(',');
+		let value: Value = serde_yaml::from_str("[docker.io/library/busybox:latest, 'sha256:abc']").unwrap();
+		assert_eq!(setting(&pins, &value).unwrap().unwrap().2, "docker.io/library/busybox:latest,sha256:abc");
+		let gc = Arg::new("gc").long("gc").env("GC");
+		assert_eq!(setting(&gc, &Value::Bool(true)).unwrap().unwrap().2, "true");
+		assert!(setting(&gc, &value).is_err());
+		assert!(setting(&gc, &Value::Null).unwrap().is_none());
+	}
+}
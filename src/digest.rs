@@ -0,0 +1,168 @@
+use std::fmt;
+use std::str::FromStr;
+
+use compact_str::CompactString;
+use serde_with::DeserializeFromStr;
+use serde_with::SerializeDisplay;
+use sha2::Digest as _;
+use sha2::Sha256;
+use sha2::Sha512;
+
+#[derive(Debug, thiserror::Error)]
+#[error("Invalid digest '{0}'; expected <algorithm>:<lowercase hex>, with algorithm sha256 or sha512")]
+pub struct InvalidDigest(pub String);
+
+/// A hash algorithm that content can be addressed by, per the OCI image spec
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Algorithm {
+	Sha256,
+	Sha512
+}
+
+impl Algorithm {
+	pub fn name(self) -> &'static str {
+		match self {
+			Self::Sha256 => "sha256",
+			Self::Sha512 => "sha512"
+		}
+	}
+
+	/// Length of the hash, in bytes
+	pub fn size(self) -> usize {
+		match self {
+			Self::Sha256 => 256 / 8,
+			Self::Sha512 => 512 / 8
+		}
+	}
+
+	pub fn hasher(self) -> Hasher {
+		match self {
+			Self::Sha256 => Hasher::Sha256(Sha256::new()),
+			Self::Sha512 => Hasher::Sha512(Sha512::new())
+		}
+	}
+
+	/// The digest of `data`
+	pub fn digest(self, data: &[u8]) -> Digest {
+		let mut hasher = self.hasher();
+		hasher.update(data);
+		hasher.finalize()
+	}
+}
+
+impl FromStr for Algorithm {
+	type Err = InvalidDigest;
+
+	fn from_str(input: &str) -> Result<Self, Self::Err> {
+		match input {
+			"sha256" => Ok(Self::Sha256),
+			"sha512" => Ok(Self::Sha512),
+			_ => Err(InvalidDigest(input.to_string()))
+		}
+	}
+}
+
+impl fmt::Display for Algorithm {
+	#[inline]
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str(self.name())
+	}
+}
+
+/// An algorithm-tagged content digest, e.g. `sha256:6864e619...`
+#[derive(Clone, Debug, PartialEq, Eq, Hash, DeserializeFromStr, SerializeDisplay)]
+pub struct Digest {
+	algorithm: Algorithm,
+	/// Lowercase hex; the canonical form, and what's used in storage paths
+	hex: CompactString
+}
+
+impl Digest {
+	pub fn algorithm(&self) -> Algorithm {
+		self.algorithm
+	}
+
+	pub fn hex(&self) -> &str {
+		self.hex.as_ref()
+	}
+
+	pub fn to_bytes(&self) -> Vec<u8> {
+		hex::decode(self.hex.as_bytes()).unwrap()
+	}
+
+	fn from_bytes(algorithm: Algorithm, bytes: &[u8]) -> Self {
+		Self { algorithm, hex: hex::encode(bytes).into() }
+	}
+}
+
+impl FromStr for Digest {
+	type Err = InvalidDigest;
+
+	fn from_str(input: &str) -> Result<Self, Self::Err> {
+		let invalid = || InvalidDigest(input.to_string());
+		let (algorithm, hex) = input.split_once(':').ok_or_else(invalid)?;
+		let algorithm = algorithm.parse::<Algorithm>().map_err(|_| invalid())?;
+		if (hex.len() != algorithm.size() * 2 || !hex.chars().all(|c| c.is_ascii_digit() || matches!(c, 'a'..='f'))) {
+			return Err(invalid());
+		}
+		Ok(Self { algorithm, hex: hex.into() })
+	}
+}
+
+impl fmt::Display for Digest {
+	#[inline]
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{}:{}", self.algorithm, self.hex)
+	}
+}
+
+/// Incrementally computes a [`Digest`] with whichever algorithm it was created for
+#[derive(Clone)]
+pub enum Hasher {
+	Sha256(Sha256),
+	Sha512(Sha512)
+}
+
+impl Hasher {
+	pub fn update(&mut self, data: &[u8]) {
+		match self {
+			Self::Sha256(h) => h.update(data),
+			Self::Sha512(h) => h.update(data)
+		}
+	}
+
+	pub fn finalize(self) -> Digest {
+		match self {
+			Self::Sha256(h) => Digest::from_bytes(Algorithm::Sha256, &h.finalize()),
+			Self::Sha512(h) => Digest::from_bytes(Algorithm::Sha512, &h.finalize())
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn parse_digests() {
+		let sha256 = "sha256:6864e61916f58174557076c34e7122753331cf28077edb0f23e1fb5419dd6acd";
+		let digest = sha256.parse::<Digest>().unwrap();
+		assert_eq!(digest.algorithm(), Algorithm::Sha256);
+		assert_eq!(digest.to_string(), sha256);
+		assert_eq!(digest.to_bytes().len(), 32);
+
+		let sha512 = Algorithm::Sha512.digest(b"");
+		assert_eq!(sha512.to_string().parse::<Digest>().unwrap(), sha512);
+		assert!(sha512.to_string().starts_with("sha512:cf83e1357eefb8bd"));
+
+		for invalid in [
+			"sha256:abcd",
+			"sha512:6864e61916f58174557076c34e7122753331cf28077edb0f23e1fb5419dd6acd",
+			"md5:d41d8cd98f00b204e9800998ecf8427e",
+			"sha256:6864E61916F58174557076C34E7122753331CF28077EDB0F23E1FB5419DD6ACD",
+			"latest"
+		] {
+			assert!(invalid.parse::<Digest>().is_err(), "{invalid}");
+		}
+	}
+}
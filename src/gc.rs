@@ -0,0 +1,180 @@
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::time::SystemTime;
+
+use clap::Parser;
+use compact_str::CompactString;
+use futures::stream::TryStreamExt;
+use humantime::Duration;
+use once_cell::sync::Lazy;
+use prometheus::register_int_counter;
+use prometheus::IntCounter;
+use serde::Serialize;
+use tracing::error;
+use tracing::info;
+
+use crate::manifest::Platform;
+use crate::manifest::References;
+use crate::storage;
+use crate::storage::Repository;
+
+#[derive(Clone, Debug, Parser)]
+pub struct GcConfig {
+	/// If enabled, each cleanup pass will also delete blobs that aren't referenced by any cached
+	/// manifest
+	#[clap(env = "GC", long = "gc", default_value_t = false)]
+	enabled: bool,
+	/// Unreferenced blobs younger than this will not be deleted, so that blobs pulled just before
+	/// the manifest that references them aren't collected
+	#[clap(env, long, default_value = "1h")]
+	gc_grace_period: Duration,
+	/// Report what would be deleted without deleting anything
+	#[clap(env, long, default_value_t = false)]
+	gc_dry_run: bool
+}
+
+#[derive(Clone, Copy, Debug, Default, Serialize)]
+pub struct Report {
+	/// Number of manifests parsed
+	pub manifests: usize,
+	/// Number of distinct blobs referenced by those manifests
+	pub referenced: usize,
+	/// Number of blobs deleted, or that would have been deleted in a dry run
+	pub deleted: usize,
+	/// Total size of blobs deleted, or that would have been deleted in a dry run
+	pub bytes: u64
+}
+
+impl GcConfig {
+	pub fn enabled(&self) -> bool {
+		self.enabled
+	}
+
+	/// Collects garbage if enabled, or if `force` is set.  `--gc-dry-run` applies on top of
+	/// `dry_run`.
+	pub async fn run(&self, repo: &Repository, pinned: &HashSet<String>, platforms: &HashMap<CompactString, Vec<Platform>>, force: bool, dry_run: bool) -> Result<Option<Report>, storage::Error> {
+		static DELETED_COUNT: Lazy<IntCounter> = Lazy::new(|| register_int_counter!("gc_deleted_blobs", "Number of unreferenced blobs deleted by garbage collection").unwrap());
+		static DELETED_BYTES: Lazy<IntCounter> = Lazy::new(|| register_int_counter!("gc_deleted_bytes", "Total size of unreferenced blobs deleted by garbage collection").unwrap());
+
+		if (!self.enabled && !force) {
+			return Ok(None);
+		}
+		let dry_run = dry_run || self.gc_dry_run;
+		let report = collect(repo, SystemTime::now() - *self.gc_grace_period, pinned, platforms, dry_run).await?;
+		if (!dry_run) {
+			DELETED_COUNT.inc_by(report.deleted.try_into().unwrap_or_default());
+			DELETED_BYTES.inc_by(report.bytes);
+		}
+		info!(manifests = report.manifests, referenced = report.referenced, deleted = report.deleted, bytes = report.bytes, dry_run, "Garbage collection complete");
+		Ok(Some(report))
+	}
+}
+
+/// Marks every blob referenced by a cached manifest, then deletes every unmarked blob last
+/// modified before `older_than`, other than those in `pinned`.  If any manifest can't be read or
+/// parsed, nothing is deleted, since we can't know what it references.
+pub async fn collect(repo: &Repository, older_than: SystemTime, pinned: &HashSet<String>, platforms: &HashMap<CompactString, Vec<Platform>>, dry_run: bool) -> Result<Report, storage::Error> {
+	let mut report = Report::default();
+	let (marked, manifests) = mark(repo, platforms).await?;
+	report.manifests = manifests;
+	report.referenced = marked.len();
+
+	let mut blobs = repo.list("blobs/");
+	while let Some(obj) = blobs.try_next().await? {
+		if (marked.contains(&obj.key) || pinned.contains(&obj.key) || obj.modified >= older_than) {
+			continue;
+		}
+		if (!dry_run) {
+			if let Err(error) = repo.delete(&obj.key).await {
+				error!(path = obj.key, %error, "Error deleting unreferenced blob");
+				continue;
+			}
+		}
+		info!(path = obj.key, size = obj.size, dry_run, "Collected unreferenced blob");
+		report.deleted += 1;
+		report.bytes += obj.size;
+	}
+	Ok(report)
+}
+
+/// The storage paths of every blob referenced by a cached manifest, along with the number of
+/// manifests read.  Manifests that are only referenced as children of indexes for platforms that
+/// their namespace doesn't care about, per `platforms`, don't count.  Fails if any manifest can't
+/// be parsed, since anything it references would go unmarked.
+pub async fn mark(repo: &Repository, platforms: &HashMap<CompactString, Vec<Platform>>) -> Result<(HashSet<String>, usize), storage::Error> {
+	let mut count = 0;
+	let mut references = Vec::new();
+	let (mut wanted, mut unwanted) = (HashSet::new(), HashSet::new());
+	let mut manifests = repo.list("manifests/");
+	while let Some(obj) = manifests.try_next().await? {
+		// Aged out or deleted since it was listed
+		let Some(refs) = References::read(repo, &obj.key).await? else {
+			continue;
+		};
+		count += 1;
+		let namespace_platforms = storage::manifest_namespace(&obj.key).and_then(|ns| platforms.get(ns)).map_or(&[][..], Vec::as_slice);
+		// Children of an index are cached alongside it, in the same repository
+		if let Some((dir, _)) = obj.key.rsplit_once('/') {
+			wanted.extend(refs.children(namespace_platforms).map(|digest| format!("{dir}/{digest}")));
+			unwanted.extend(refs.excluded_children(namespace_platforms).map(|digest| format!("{dir}/{digest}")));
+		}
+		let blobs = refs.blob_paths(&obj.key).collect::<Vec<_>>();
+		references.push((obj.key, blobs));
+	}
+
+	let mut marked = HashSet::new();
+	for (path, blobs) in references {
+		if (unwanted.contains(&path) && !wanted.contains(&path)) {
+			continue;
+		}
+		marked.extend(blobs);
+	}
+	Ok((marked, count))
+}
+
+#[cfg(test)]
+mod tests {
+	use bytes::Bytes;
+	use dkregistry::mediatypes::MediaTypes;
+
+	use super::*;
+	use crate::digest::Algorithm;
+	use crate::storage::filesystem;
+	use crate::storage::Manifest;
+	use crate::storage::Origin;
+	use crate::storage::StorageConfig;
+
+	#[actix_web::test]
+	async fn unparseable_manifest() {
+		let root = std::env::temp_dir().join(format!("oci-registry-gc-{}", std::process::id()));
+		let repo = StorageConfig::Filesystem(filesystem::Config::parse_from(["filesystem", "--root", root.to_str().unwrap()]))
+			.repository()
+			.unwrap();
+		let write = |path: String, body: Vec<u8>| {
+			let repo = repo.clone();
+			async move {
+				let len = body.len().try_into().unwrap();
+				repo.write(&path, futures::stream::iter([Ok::<_, std::io::Error>(Bytes::from(body))]), len, &Origin::new("docker.io", "library/busybox"))
+					.await
+					.unwrap();
+			}
+		};
+		let config = Algorithm::Sha256.digest(b"config");
+		let image = format!(r#"{{"schemaVersion": 2, "config": {{"digest": "{config}", "size": 6}}, "layers": []}}"#);
+		let manifest = serde_json::to_vec(&Manifest::new(Bytes::from(image), MediaTypes::ManifestV2S2, None)).unwrap();
+		write(storage::manifest_path("docker.io", "library/busybox", "latest"), manifest.clone()).await;
+		// Cut off partway through, as if by a crash mid-write
+		let truncated = storage::manifest_path("docker.io", "library/busybox", "truncated");
+		write(truncated.clone(), manifest[..manifest.len() / 2].to_vec()).await;
+
+		let result = mark(&repo, &HashMap::new()).await;
+		assert!(matches!(result, Err(storage::Error::InvalidManifest(ref path, _)) if *path == truncated));
+		assert!(collect(&repo, SystemTime::now(), &HashSet::new(), &HashMap::new(), true).await.is_err());
+
+		repo.delete(&truncated).await.unwrap();
+		let (marked, count) = mark(&repo, &HashMap::new()).await.unwrap();
+		assert_eq!(count, 1);
+		assert!(marked.contains(&storage::blob_path(&config)));
+		std::fs::remove_dir_all(root).unwrap();
+	}
+}
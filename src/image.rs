@@ -8,15 +8,13 @@ use lazy_regex::Lazy;
 use lazy_regex::Regex;
 use serde_with::DeserializeFromStr;
 
+use crate::digest::Digest;
+
 mod error;
 
 static RE_IMAGE: Lazy<Regex> = lazy_regex!("^[a-z0-9]+([._-][a-z0-9]+)*(/[a-z0-9]+([._-][a-z0-9]+)*)*$");
 static RE_TAG: Lazy<Regex> = lazy_regex!("^[a-zA-Z0-9_][a-zA-Z0-9._-]{0,127}$");
 
-fn is_valid_sha256(s: &str) -> bool {
-	s.len() == 64 && s.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit()) && hex::decode(s).is_ok()
-}
-
 #[derive(Debug, DeserializeFromStr)]
 pub struct ImageName(CompactString);
 impl FromStr for ImageName {
@@ -44,24 +42,25 @@ impl fmt::Display for ImageName {
 	}
 }
 
-#[derive(Debug, DeserializeFromStr)]
+#[derive(Clone, Debug, DeserializeFromStr)]
 pub enum ImageReference {
 	Tag(CompactString),
-	Sha256(String)
+	Digest(Digest)
 }
 
 impl FromStr for ImageReference {
 	type Err = error::InvalidImageReference;
 
 	fn from_str(input: &str) -> Result<Self, Self::Err> {
-		match input.strip_prefix("sha256:") {
-			None => match RE_TAG.is_match(input) {
+		// Tags can't contain a colon, so anything that does must be a digest
+		match input.contains(':') {
+			false => match RE_TAG.is_match(input) {
 				false => Err(error::InvalidImageReference(input.to_string())),
 				true => Ok(ImageReference::Tag(input.into()))
 			},
-			Some(s) => match is_valid_sha256(s) {
-				false => Err(error::InvalidImageReference(input.to_string())),
-				true => Ok(ImageReference::Sha256(s.into()))
+			true => match input.parse() {
+				Err(_) => Err(error::InvalidImageReference(input.to_string())),
+				Ok(digest) => Ok(ImageReference::Digest(digest))
 			}
 		}
 	}
@@ -72,7 +71,7 @@ impl fmt::Display for ImageReference {
 	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
 		match self {
 			Self::Tag(s) => s.fmt(f),
-			Self::Sha256(s) => write!(f, "sha256:{s}")
+			Self::Digest(d) => d.fmt(f)
 		}
 	}
 }
@@ -81,7 +80,7 @@ impl ImageReference {
 	pub fn to_str(&self) -> Cow<'_, str> {
 		match self {
 			Self::Tag(s) => Cow::Borrowed(s.as_ref()),
-			Self::Sha256(s) => Cow::Owned(format!("sha256:{s}"))
+			Self::Digest(d) => Cow::Owned(d.to_string())
 		}
 	}
 }
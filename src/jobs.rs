@@ -0,0 +1,151 @@
+use std::collections::BTreeMap;
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use serde::Serialize;
+
+/// How many finished jobs are remembered for their status to be looked up
+const FINISHED_JOBS: usize = 100;
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "snake_case", tag = "state")]
+pub enum State {
+	Running,
+	Succeeded,
+	Failed { error: String }
+}
+
+/// A long-running administrative task, run in the background
+#[derive(Clone, Debug, Serialize)]
+pub struct Job {
+	id: u64,
+	description: String,
+	#[serde(flatten)]
+	state: State,
+	#[serde(serialize_with = "rfc3339")]
+	started: SystemTime,
+	#[serde(serialize_with = "rfc3339_opt", skip_serializing_if = "Option::is_none")]
+	finished: Option<SystemTime>,
+	/// Counts of what's been done so far, e.g. the number of objects deleted
+	progress: BTreeMap<&'static str, u64>,
+	/// A detailed account of what the job did, for those that provide one
+	#[serde(skip_serializing_if = "Option::is_none")]
+	report: Option<serde_json::Value>
+}
+
+fn rfc3339<S: serde::Serializer>(time: &SystemTime, serializer: S) -> Result<S::Ok, S::Error> {
+	serializer.collect_str(&humantime::format_rfc3339_seconds(*time))
+}
+
+fn rfc3339_opt<S: serde::Serializer>(time: &Option<SystemTime>, serializer: S) -> Result<S::Ok, S::Error> {
+	match time {
+		Some(time) => rfc3339(time, serializer),
+		None => serializer.serialize_none()
+	}
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+	next_id: u64,
+	running: BTreeMap<u64, Job>,
+	finished: VecDeque<Job>
+}
+
+/// Jobs that are running, and the most recent of those that have finished
+#[derive(Clone, Debug, Default)]
+pub struct Jobs(Arc<Mutex<Inner>>);
+
+impl Jobs {
+	pub fn start(&self, description: impl Into<String>) -> JobHandle {
+		let mut inner = self.0.lock().unwrap();
+		inner.next_id += 1;
+		let id = inner.next_id;
+		let job = Job {
+			id,
+			description: description.into(),
+			state: State::Running,
+			started: SystemTime::now(),
+			finished: None,
+			progress: BTreeMap::new(),
+			report: None
+		};
+		inner.running.insert(id, job);
+		JobHandle { jobs: self.clone(), id }
+	}
+
+	pub fn get(&self, id: u64) -> Option<Job> {
+		let inner = self.0.lock().unwrap();
+		inner.running.get(&id).or_else(|| inner.finished.iter().find(|job| job.id == id)).cloned()
+	}
+
+	/// Every running job, followed by finished jobs from most to least recent
+	pub fn list(&self) -> Vec<Job> {
+		let inner = self.0.lock().unwrap();
+		inner.running.values().chain(inner.finished.iter()).cloned().collect()
+	}
+}
+
+/// Used by a job to report its progress and, eventually, its result
+#[derive(Debug)]
+pub struct JobHandle {
+	jobs: Jobs,
+	id: u64
+}
+
+impl JobHandle {
+	pub fn id(&self) -> u64 {
+		self.id
+	}
+
+	pub fn add(&self, counter: &'static str, n: u64) {
+		if let Some(job) = self.jobs.0.lock().unwrap().running.get_mut(&self.id) {
+			*job.progress.entry(counter).or_default() += n;
+		}
+	}
+
+	pub fn report<T: Serialize>(&self, report: &T) {
+		let report = serde_json::to_value(report).ok();
+		if let Some(job) = self.jobs.0.lock().unwrap().running.get_mut(&self.id) {
+			job.report = report;
+		}
+	}
+
+	/// Records how the job ended, and returns it as it'll be reported from now on
+	pub fn finish<E: fmt::Display>(self, result: Result<(), E>) -> Option<Job> {
+		let mut inner = self.jobs.0.lock().unwrap();
+		let Some(mut job) = inner.running.remove(&self.id) else {
+			return None;
+		};
+		job.state = match result {
+			Ok(_) => State::Succeeded,
+			Err(error) => State::Failed { error: error.to_string() }
+		};
+		job.finished = Some(SystemTime::now());
+		inner.finished.push_front(job.clone());
+		inner.finished.truncate(FINISHED_JOBS);
+		Some(job)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn job_lifecycle() {
+		let jobs = Jobs::default();
+		let job = jobs.start("test");
+		let id = job.id();
+		job.add("objects", 2);
+		job.add("objects", 3);
+		assert!(matches!(jobs.get(id).unwrap().state, State::Running));
+		job.finish(Err("oops"));
+		let finished = jobs.get(id).unwrap();
+		assert!(matches!(finished.state, State::Failed { .. }));
+		assert_eq!(finished.progress.get("objects"), Some(&5));
+		assert_eq!(jobs.list().len(), 1);
+	}
+}
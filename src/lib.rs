@@ -2,7 +2,15 @@
 #![allow(unused_parens)]
 
 pub mod api;
+mod cleanup;
+mod digest;
+mod gc;
 mod image;
+mod jobs;
+mod manifest;
+mod pin;
+mod prefetch;
+mod purge;
 mod storage;
 mod upstream;
 mod util;
@@ -1,50 +1,175 @@
 #![allow(unused_parens)]
 use core::future;
-use core::time::Duration;
-use std::time::SystemTime;
 
 use actix_web::dev::Service;
 use actix_web::http::header::HeaderName;
 use actix_web::http::header::HeaderValue;
+use actix_web::rt;
 use actix_web::web;
 use actix_web::HttpResponse;
 use actix_web_prometheus::PrometheusMetricsBuilder;
+use camino::Utf8PathBuf;
+use clap::CommandFactory;
 use clap::Parser;
+use clap::Subcommand;
 use compact_str::CompactString;
 use futures::future::FutureExt;
+use prometheus::Encoder;
+use prometheus::TextEncoder;
 use tokio::sync::oneshot;
 use tracing::error;
-use tracing::info;
-use tracing::warn;
+use tracing::Instrument;
 
 mod api;
+mod cleanup;
+mod config_file;
+mod digest;
+mod gc;
 mod image;
+mod jobs;
+mod manifest;
+mod pin;
+mod prefetch;
+mod purge;
+mod scrub;
 mod storage;
+mod telemetry;
 mod upstream;
 mod util;
 
+use api::access_log::Format as AccessLogFormat;
+use cleanup::CleanupConfig;
+use config_file::ConfigFile;
+use config_file::Problem;
+use pin::Pin;
+use pin::Pins;
+use prefetch::PrefetchConfig;
+use scrub::ScrubConfig;
 use storage::StorageConfig;
-use upstream::InvalidationConfig;
+use upstream::Clients;
 use upstream::UpstreamConfig;
 
 #[derive(Debug, Parser)]
 struct Config {
+	/// A YAML file with any of these settings, named as they are here but with underscores, along
+	/// with `storage` (the settings of a storage backend, plus `mode`:  "s3", "filesystem", or
+	/// "tiered") and `upstreams` (as in --upstream-config-file).  Environment variables and flags
+	/// override it.
+	#[clap(env, long)]
+	config_file: Option<Utf8PathBuf>,
 	/// An IP address and port combination to listen on a network socket, or a path prefixed with
 	/// "unix:" to listen on a Unix domain socket
 	#[clap(env, long, default_value = "0.0.0.0:80")]
 	listen: socket_address::Address,
+	/// If set, the admin API, metrics, and health checks are served only here, in the same form
+	/// as --listen, rather than alongside the registry, so that the registry can be exposed without
+	/// exposing them
+	#[clap(env, long)]
+	admin_listen: Option<socket_address::Address>,
+	/// How registry requests are logged.  JSON records include the namespace each request
+	/// resolved to, whether it was served from cache, upstream's status, and the number of bytes
+	/// sent before the response finished or the client went away.
+	#[clap(env, long, value_enum, default_value = "apache")]
+	access_log_format: AccessLogFormat,
 	#[clap(env, long, default_value = "docker.io")]
 	default_namespace: CompactString,
-	/// If enabled, will validate a blob's SHA256 digest when reading it from cache storage; if the
-	/// digest doesn't match what was expected based on the request URL, it will be deleted from
-	/// storage and re-retrieved from upstream.  This has an impact on performance, as the entire
-	/// blob needs to be read from storage twice instead of just once.
-	#[clap(env, long, default_value_t = false)]
+	/// If enabled, will validate a blob's digest as it's served from cache storage; if the digest
+	/// doesn't match what was expected based on the request URL, the response will be aborted and
+	/// the blob will be deleted from storage, so that the client's retry re-retrieves it from
+	/// upstream.  Disable with `--check-cache-digest=false`.
+	#[clap(env, long, action = clap::ArgAction::Set, num_args = 0..=1, default_missing_value = "true", default_value_t = true)]
 	check_cache_digest: bool,
+	/// Images that will never be aged out, garbage collected, or evicted, along with everything
+	/// they reference, given as <namespace>/<image>:<tag>, <namespace>/<image>@<digest>, or a bare
+	/// digest.  More can be added at runtime via the admin API.
+	#[clap(env = "PINS", long = "pin", value_delimiter = ',')]
+	pins: Vec<Pin>,
 	#[clap(flatten)]
 	upstream: UpstreamConfig,
+	#[clap(flatten)]
+	cleanup: CleanupConfig,
+	#[clap(flatten)]
+	prefetch: PrefetchConfig,
+	#[clap(flatten)]
+	scrub: ScrubConfig,
 	#[clap(subcommand)]
-	storage: StorageConfig
+	command: Command
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+	/// Pull every image in --prefetch-list into the cache, then exit
+	Prefetch {
+		#[clap(subcommand)]
+		storage: StorageConfig
+	},
+	/// Check the configuration, including --config-file, for errors, then exit
+	CheckConfig {
+		/// Also check that storage and every configured upstream can be reached
+		#[clap(long)]
+		connect: bool,
+		#[clap(subcommand)]
+		storage: StorageConfig
+	},
+	#[clap(flatten)]
+	Serve(StorageConfig)
+}
+
+fn exit_with(problems: Vec<Problem>) -> ! {
+	for problem in problems {
+		eprintln!("{problem}");
+	}
+	std::process::exit(2);
+}
+
+/// Finds the problems with the configuration that only show up when it's used, and with
+/// `connect`, whether storage and each upstream can be reached.  Returns the exit code.
+async fn check_config(upstream: &UpstreamConfig, storage: &StorageConfig, connect: bool) -> i32 {
+	let mut ok = true;
+	let clients = match upstream.clients().await {
+		Ok(v) => Some(v),
+		Err(error) => {
+			eprintln!("{error}");
+			ok = false;
+			None
+		}
+	};
+	let repo = match storage.repository() {
+		Ok(v) => Some(v),
+		Err(error) => {
+			eprintln!("storage: {error}");
+			ok = false;
+			None
+		}
+	};
+	if (connect) {
+		// Not finding it is as good as finding it
+		if let Some(repo) = repo {
+			match repo.read_shared("check-config").await {
+				Err(error) if !error.is_not_found() => {
+					eprintln!("storage: {error}");
+					ok = false;
+				},
+				_ => println!("storage: OK")
+			};
+		}
+		for (namespace, client) in clients.iter().flat_map(Clients::iter) {
+			match client.client.clone().authenticate(&[]).await {
+				Ok(_) => println!("{namespace}: OK"),
+				Err(error) => {
+					eprintln!("{namespace}: {error}");
+					ok = false;
+				}
+			};
+		}
+	}
+	match ok {
+		true => {
+			println!("Configuration OK");
+			0
+		},
+		false => 1
+	}
 }
 
 #[inline]
@@ -52,103 +177,215 @@ fn liveness() -> future::Ready<HttpResponse> {
 	future::ready(HttpResponse::Ok().body(""))
 }
 
-#[allow(dead_code)] // TODO:  Implement
 #[inline]
 async fn readiness() -> Result<&'static str, api::error::Error> {
 	// TODO:  Check upstream and storage
 	Ok("")
 }
 
-async fn cleanup(upstream: &InvalidationConfig, repo: &storage::Repository) {
-	let now = SystemTime::now();
-	let mut count = match repo.delete_old_blobs(now - upstream.blob).await {
-		Ok(v) => v,
+async fn metrics() -> HttpResponse {
+	let encoder = TextEncoder::new();
+	let mut body = Vec::new();
+	match encoder.encode(&prometheus::gather(), &mut body) {
+		Ok(_) => HttpResponse::Ok().content_type(encoder.format_type()).body(body),
 		Err(error) => {
-			error!(%error, "Error cleaning up blobs");
-			0
+			error!(%error, "Error encoding metrics");
+			HttpResponse::InternalServerError().finish()
 		}
-	};
-	for (ns, age) in upstream.manifests.iter() {
-		let ns: &str = ns.as_ref();
-		match repo.delete_old_manifests(ns, now - *age).await {
-			Ok(v) => count += v,
-			Err(error) => error!(%error, namespace = ns, "Error cleaning up manifests")
-		};
 	}
+}
 
-	if (count > 0) {
-		warn!(count, "Aged out objects");
-	} else {
-		info!(count, "Aged out objects");
-	}
+/// Binds `server` to a network or Unix socket address
+macro_rules! bind {
+	($server:expr, $address:expr) => {
+		match $address {
+			socket_address::Address::Network(addr) => $server.bind(addr),
+			socket_address::Address::UnixSocket(path) => $server.bind_uds(path)
+		}
+	};
+}
+
+/// Routes for pulling images
+fn registry_routes(cfg: &mut web::ServiceConfig, access_log_format: AccessLogFormat) {
+	cfg.service(
+		web::scope("/v2")
+			.wrap(actix_web::middleware::Condition::new(matches!(access_log_format, AccessLogFormat::Apache), actix_web::middleware::Logger::default()))
+			.wrap_fn(move |req, srv| api::access_log::log(access_log_format, req, srv))
+			.route("/", web::get().to(api::root))
+			// /v2/library/telegraf/manifests/1.24-alpine
+			// /v2/library/redis/manifests/sha256:226cbafc637cd58cf008bf87ec9d1548ad1b672ef4279433495bdff100cdb883
+			// /v2/docker.io/library/telegraf/manifests/1.24-alpine
+			// /v2/docker.io/library/redis/manifests/sha256:226cbafc637cd58cf008bf87ec9d1548ad1b672ef4279433495bdff100cdb883
+			.route("/{image:[^{}]+}/manifests/{reference}", web::head().to(api::manifest))
+			.route("/{image:[^{}]+}/manifests/{reference}", web::get().to(api::manifest))
+			// /v2/grafana/grafana/blobs/sha256:6864e61916f58174557076c34e7122753331cf28077edb0f23e1fb5419dd6acd
+			// /v2/docker.io/grafana/grafana/blobs/sha256:6864e61916f58174557076c34e7122753331cf28077edb0f23e1fb5419dd6acd
+			.route("/{image:[^{}]+}/blobs/{digest}", web::get().to(api::blob))
+			.wrap_fn(|req, srv| {
+				let span = telemetry::request_span(&req);
+				srv.call(req).instrument(span.clone()).map(move |response| {
+					if let Ok(ok) = &response {
+						span.record("http.status_code", ok.status().as_u16());
+					}
+					response.map(|mut ok| {
+						ok.headers_mut()
+							.insert(HeaderName::from_static("docker-distribution-api-version"), HeaderValue::from_static("registry/2.0"));
+						ok
+					})
+				})
+			})
+	);
+}
+
+/// Routes for inspecting and managing the cache, along with metrics and health checks, none of
+/// which should be exposed to the public
+fn admin_routes(cfg: &mut web::ServiceConfig) {
+	cfg.service(
+		web::scope("/_admin")
+			.wrap(actix_web::middleware::Logger::default())
+			.route("/namespaces", web::get().to(api::admin::namespaces))
+			// /_admin/blobs/sha256:6864e61916f58174557076c34e7122753331cf28077edb0f23e1fb5419dd6acd
+			.route("/blobs/{digest}", web::get().to(api::admin::blob))
+			.route("/jobs", web::get().to(api::admin::jobs))
+			.route("/jobs/{id}", web::get().to(api::admin::job))
+			.route("/cleanup", web::get().to(api::admin::last_cleanup))
+			.route("/cleanup", web::post().to(api::admin::cleanup))
+			.route("/pins", web::get().to(api::list_pins))
+			// /_admin/pins/docker.io/library/busybox:latest
+			.route("/pins/{pin:.+}", web::put().to(api::pin))
+			.route("/pins/{pin:.+}", web::delete().to(api::unpin))
+			// /_admin/docker.io/repositories
+			.route("/{namespace}/repositories", web::get().to(api::admin::repositories))
+			// /_admin/docker.io/library/busybox/manifests
+			.route("/{image:[^{}]+}/manifests", web::get().to(api::admin::manifests))
+			.route("/{image:[^{}]+}/manifests/{reference}", web::delete().to(api::delete_manifest))
+			.route("/{image:[^{}]+}/blobs/{digest}", web::delete().to(api::delete_blob))
+			// Registered last, so that the more specific routes above take precedence
+			.route("/{namespace}", web::delete().to(api::admin::purge_namespace))
+			// /_admin/docker.io/library/busybox?tag=1.3*&cascade=true
+			.route("/{namespace}/{repository:.+}", web::delete().to(api::admin::purge_repository))
+	)
+	.route("/metrics", web::get().to(metrics))
+	.route("/ready", web::get().to(readiness))
+	.route("/", web::get().to(liveness));
 }
 
 #[actix_web::main]
 async fn main() {
-	let config = Config::parse();
+	let mut file = ConfigFile::load(&Config::command()).unwrap_or_else(|problems| exit_with(problems));
+	let mut config: Config = config_file::parse(file.as_ref()).unwrap_or_else(|problems| exit_with(problems));
+	config.upstream.set_upstreams(file.as_mut().and_then(ConfigFile::upstreams));
+
+	// JSON access log records go to stdout, which mustn't have anything else mixed in
+	telemetry::init(matches!(config.access_log_format, AccessLogFormat::Json));
+
+	let (storage, prefetch_only) = match config.command {
+		Command::Prefetch { storage } => (storage, true),
+		Command::CheckConfig { connect, storage } => std::process::exit(check_config(&config.upstream, &storage, connect).await),
+		Command::Serve(storage) => (storage, false)
+	};
+	let upstream = match config.upstream.clients().await {
+		Ok(v) => v,
+		Err(error) => {
+			error!(%error, "Invalid upstream configuration");
+			std::process::exit(1);
+		}
+	};
+	let repo = match storage.repository() {
+		Ok(v) => v,
+		Err(error) => {
+			error!(%error, "Error setting up storage");
+			std::process::exit(1);
+		}
+	};
+	{
+		// Walking the whole cache can take a while, and nothing needs to wait for it
+		let repo = repo.clone();
+		rt::spawn(async move { repo.delete_temp_files("").await });
+	}
+	let pins = Pins::new(config.pins);
+	let cleaner = config.cleanup.cleaner(repo.clone(), pins.clone());
+
+	if (prefetch_only) {
+		let per_request_config = web::Data::new(api::RequestConfig::new(repo, upstream, config.default_namespace, config.check_cache_digest, pins, config.prefetch.background(), cleaner));
+		let succeeded = match config.prefetch.run(&per_request_config).await {
+			Ok(report) => report.failed == 0,
+			Err(error) => {
+				error!(%error, "Error prefetching images");
+				false
+			}
+		};
+		telemetry::shutdown();
+		match succeeded {
+			true => return,
+			false => std::process::exit(1)
+		};
+	}
 
-	tracing_subscriber::fmt().with_env_filter(tracing_subscriber::EnvFilter::from_default_env()).compact().init();
+	// Served by `metrics` rather than by the middleware, so that it's only on the admin listener
+	let prometheus = PrometheusMetricsBuilder::new("http").build().unwrap();
+	let per_request_config = web::Data::new(api::RequestConfig::new(
+		repo.clone(),
+		upstream,
+		config.default_namespace,
+		config.check_cache_digest,
+		pins,
+		config.prefetch.background(),
+		cleaner.clone()
+	));
 
-	let repo = config.storage.repository();
-	let upstream = config.upstream.clients().await.unwrap();
 	let (shutdown_tx, mut shutdown_rx) = oneshot::channel();
 	let background = {
-		let repo = repo.clone();
-		let upstream = upstream.invalidation_config();
-		tokio::task::spawn(async move {
-			let mut interval = tokio::time::interval(Duration::from_secs(300));
+		let upstream = per_request_config.invalidation_config().await;
+		let jobs = per_request_config.jobs().clone();
+		rt::spawn(async move {
+			let mut interval = tokio::time::interval(cleaner.interval());
 			loop {
 				tokio::select! {
 					_ = interval.tick() => (),
 					_ = &mut shutdown_rx => break
 				};
-				cleanup(&upstream, &repo).await;
+				cleaner.run_scheduled(&upstream, &jobs).await;
 			}
 		})
 	};
 
-	let prometheus = PrometheusMetricsBuilder::new("http").endpoint("/metrics").build().unwrap();
-	let per_request_config = web::Data::new(api::RequestConfig::new(repo, upstream, config.default_namespace, config.check_cache_digest));
+	{
+		let repo = repo.clone();
+		rt::spawn(async move { config.scrub.schedule(repo).await });
+	}
+
+	{
+		let per_request_config = per_request_config.clone();
+		rt::spawn(async move { config.prefetch.schedule(per_request_config).await });
+	}
 
+	let separate_admin = config.admin_listen.is_some();
+	let admin_server = config.admin_listen.map(|address| {
+		let per_request_config = per_request_config.clone();
+		let prometheus = prometheus.clone();
+		let server = actix_web::HttpServer::new(move || actix_web::App::new().app_data(per_request_config.clone()).wrap(prometheus.clone()).configure(admin_routes));
+		bind!(server.shutdown_timeout(10), &address).unwrap().run()
+	});
+	let access_log_format = config.access_log_format;
 	let server = actix_web::HttpServer::new(move || {
-		actix_web::App::new()
+		let app = actix_web::App::new()
 			.app_data(per_request_config.clone())
 			.wrap(prometheus.clone())
-			.service(
-				web::scope("/v2")
-					.wrap(actix_web::middleware::Logger::default())
-					.route("/", web::get().to(api::root))
-					// /v2/library/telegraf/manifests/1.24-alpine
-					// /v2/library/redis/manifests/sha256:226cbafc637cd58cf008bf87ec9d1548ad1b672ef4279433495bdff100cdb883
-					// /v2/docker.io/library/telegraf/manifests/1.24-alpine
-					// /v2/docker.io/library/redis/manifests/sha256:226cbafc637cd58cf008bf87ec9d1548ad1b672ef4279433495bdff100cdb883
-					.route("/{image:[^{}]+}/manifests/{reference}", web::head().to(api::manifest))
-					.route("/{image:[^{}]+}/manifests/{reference}", web::get().to(api::manifest))
-					// /v2/grafana/grafana/blobs/sha256:6864e61916f58174557076c34e7122753331cf28077edb0f23e1fb5419dd6acd
-					// /v2/docker.io/grafana/grafana/blobs/sha256:6864e61916f58174557076c34e7122753331cf28077edb0f23e1fb5419dd6acd
-					.route("/{image:[^{}]+}/blobs/{digest}", web::get().to(api::blob))
-					.wrap_fn(|req, srv| {
-						srv.call(req).map(|response| {
-							response.map(|mut ok| {
-								ok.headers_mut()
-									.insert(HeaderName::from_static("docker-distribution-api-version"), HeaderValue::from_static("registry/2.0"));
-								ok
-							})
-						})
-					})
-			)
-			.service(
-				web::scope("/_admin")
-					.wrap(actix_web::middleware::Logger::default())
-					.route("/{image:[^{}]+}/manifests/{reference}", web::delete().to(api::delete_manifest))
-					.route("/{image:[^{}]+}/blobs/{digest}", web::delete().to(api::delete_blob))
-			)
-			.route("/", web::get().to(liveness))
+			.configure(|cfg| registry_routes(cfg, access_log_format));
+		match separate_admin {
+			true => app,
+			false => app.configure(admin_routes)
+		}
 	});
-	match config.listen {
-		socket_address::Address::Network(addr) => server.shutdown_timeout(10).bind(&addr).unwrap().run().await.unwrap(),
-		socket_address::Address::UnixSocket(path) => server.shutdown_timeout(10).bind_uds(&path).unwrap().run().await.unwrap()
+	let server = bind!(server.shutdown_timeout(10), &config.listen).unwrap().run();
+	match admin_server {
+		Some(admin_server) => {
+			futures::try_join!(server, admin_server).unwrap();
+		},
+		None => server.await.unwrap()
 	};
 	shutdown_tx.send(()).unwrap();
 	background.await.unwrap();
+	telemetry::shutdown();
 }
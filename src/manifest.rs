@@ -0,0 +1,232 @@
+use core::fmt;
+use core::str::FromStr;
+
+use compact_str::CompactString;
+use futures::stream::TryStreamExt;
+use serde::Deserialize;
+use tracing::warn;
+
+use crate::digest::Digest;
+use crate::storage;
+use crate::storage::Manifest;
+use crate::storage::Repository;
+
+/// The parts of an image manifest, image index, or Docker manifest list that refer to other
+/// content.  Anything else in the document is ignored.
+#[derive(Debug, Default, Deserialize)]
+pub struct References {
+	/// Present in image manifests
+	#[serde(default)]
+	pub config: Option<Descriptor>,
+	/// Present in image manifests
+	#[serde(default)]
+	pub layers: Vec<Descriptor>,
+	/// Present in image indexes and Docker manifest lists
+	#[serde(default)]
+	manifests: Vec<Descriptor>,
+	/// Present in Docker schema 1 manifests
+	#[serde(default, rename = "fsLayers")]
+	fs_layers: Vec<V1Layer>
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Descriptor {
+	pub digest: String,
+	/// Present in the children of image indexes and Docker manifest lists
+	#[serde(default)]
+	pub platform: Option<Platform>
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("Invalid platform '{0}'; expected <os>/<architecture> or <os>/<architecture>/<variant>")]
+pub struct InvalidPlatform(pub String);
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+pub struct Platform {
+	pub os: CompactString,
+	pub architecture: CompactString,
+	#[serde(default)]
+	pub variant: Option<CompactString>
+}
+
+impl FromStr for Platform {
+	type Err = InvalidPlatform;
+
+	fn from_str(input: &str) -> Result<Self, Self::Err> {
+		let mut parts = input.split('/');
+		match (parts.next(), parts.next(), parts.next(), parts.next()) {
+			(Some(os), Some(architecture), variant, None) if !os.is_empty() && !architecture.is_empty() => Ok(Self {
+				os: os.into(),
+				architecture: architecture.into(),
+				variant: variant.map(Into::into)
+			}),
+			_ => Err(InvalidPlatform(input.to_string()))
+		}
+	}
+}
+
+impl fmt::Display for Platform {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self.variant.as_ref() {
+			Some(variant) => write!(f, "{}/{}/{variant}", self.os, self.architecture),
+			None => write!(f, "{}/{}", self.os, self.architecture)
+		}
+	}
+}
+
+impl Platform {
+	/// Whether `other` is this platform; if this has no variant, any variant of the same OS and
+	/// architecture matches
+	pub fn matches(&self, other: &Platform) -> bool {
+		self.os == other.os && self.architecture == other.architecture && (self.variant.is_none() || self.variant == other.variant)
+	}
+}
+
+/// Whether a child of an index for `platform` is wanted, given the allowed `platforms`.  If no
+/// platforms are given, everything is allowed; children that don't specify a platform are always
+/// wanted, since we can't tell what they're for.
+fn is_wanted(platforms: &[Platform], platform: Option<&Platform>) -> bool {
+	platforms.is_empty() || platform.map_or(true, |p| platforms.iter().any(|wanted| wanted.matches(p)))
+}
+
+/// Removes the children of an image index or Docker manifest list that aren't for one of
+/// `platforms`.  Returns `None` if `manifest` isn't an index, or if nothing would be removed.
+pub fn filter_index(manifest: &[u8], platforms: &[Platform]) -> Result<Option<Vec<u8>>, serde_json::Error> {
+	let mut document = serde_json::from_slice::<serde_json::Value>(manifest)?;
+	let Some(children) = document.get_mut("manifests").and_then(|v| v.as_array_mut()) else {
+		return Ok(None);
+	};
+	let count = children.len();
+	children.retain(|child| is_wanted(platforms, child.get("platform").and_then(|p| Platform::deserialize(p).ok()).as_ref()));
+	if (children.len() == count) {
+		return Ok(None);
+	}
+	serde_json::to_vec(&document).map(Some)
+}
+
+#[derive(Debug, Deserialize)]
+struct V1Layer {
+	#[serde(rename = "blobSum")]
+	blob_sum: String
+}
+
+impl References {
+	pub fn parse(manifest: &[u8]) -> Result<Self, serde_json::Error> {
+		serde_json::from_slice(manifest)
+	}
+
+	/// Digests of every blob this manifest refers to directly.  Child manifests of an index are
+	/// not included; they need to be fetched and parsed themselves.
+	pub fn blobs(&self) -> impl Iterator<Item = &str> {
+		self.config
+			.iter()
+			.chain(self.layers.iter())
+			.map(|d| d.digest.as_str())
+			.chain(self.fs_layers.iter().map(|l| l.blob_sum.as_str()))
+	}
+
+	/// Storage paths of the blobs this references, per [`blobs`](Self::blobs).  The manifest at
+	/// `path` comes from upstream, so a digest that isn't valid is skipped rather than trusted to
+	/// build a path from.
+	pub fn blob_paths<'a>(&'a self, path: &'a str) -> impl Iterator<Item = String> + 'a {
+		self.blobs().filter_map(move |digest| match digest.parse::<Digest>() {
+			Ok(v) => Some(storage::blob_path(&v)),
+			Err(error) => {
+				warn!(path, %error, "Ignoring invalid blob digest in cached manifest");
+				None
+			}
+		})
+	}
+
+	/// Digests of the child manifests of an index that are for one of `platforms`, or all of them
+	/// if `platforms` is empty.  Children that don't specify a platform are always included.
+	pub fn children<'a>(&'a self, platforms: &'a [Platform]) -> impl Iterator<Item = &'a str> {
+		self.manifests.iter().filter(|d| is_wanted(platforms, d.platform.as_ref())).map(|d| d.digest.as_str())
+	}
+
+	/// Digests of the child manifests of an index that [`children`](Self::children) leaves out
+	pub fn excluded_children<'a>(&'a self, platforms: &'a [Platform]) -> impl Iterator<Item = &'a str> {
+		self.manifests.iter().filter(|d| !is_wanted(platforms, d.platform.as_ref())).map(|d| d.digest.as_str())
+	}
+
+	/// Reads the manifest cached at `path` and parses out its references.  Returns `None` if it
+	/// isn't there anymore, and [`storage::Error::InvalidManifest`] if it can't be parsed, e.g.
+	/// because it's truncated or of a media type we don't know.
+	pub async fn read(repo: &Repository, path: &str) -> Result<Option<Self>, storage::Error> {
+		let stream = match repo.read(path, core::time::Duration::MAX).await {
+			Ok(v) => v,
+			Err(e) if e.is_not_found() => return Ok(None),
+			Err(e) => return Err(e)
+		};
+		let body = stream.into_inner().try_collect::<bytes::BytesMut>().await?;
+		match serde_json::from_slice::<Manifest>(body.as_ref()).map(|m| Self::parse(m.manifest.as_ref())) {
+			Ok(Ok(v)) => Ok(Some(v)),
+			Ok(Err(error)) | Err(error) => Err(storage::Error::InvalidManifest(path.to_owned(), error.into()))
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn parse_image_manifest() {
+		let manifest = br#"{
+			"schemaVersion": 2,
+			"mediaType": "application/vnd.oci.image.manifest.v1+json",
+			"config": {"mediaType": "application/vnd.oci.image.config.v1+json", "digest": "sha256:aaaa", "size": 1},
+			"layers": [
+				{"mediaType": "application/vnd.oci.image.layer.v1.tar+gzip", "digest": "sha256:bbbb", "size": 2},
+				{"mediaType": "application/vnd.oci.image.layer.v1.tar+gzip", "digest": "sha256:cccc", "size": 3}
+			]
+		}"#;
+		let refs = References::parse(manifest).unwrap();
+		assert_eq!(refs.blobs().collect::<Vec<_>>(), vec!["sha256:aaaa", "sha256:bbbb", "sha256:cccc"]);
+	}
+
+	#[test]
+	fn parse_index() {
+		let manifest = br#"{
+			"schemaVersion": 2,
+			"mediaType": "application/vnd.docker.distribution.manifest.list.v2+json",
+			"manifests": [
+				{"digest": "sha256:aaaa", "size": 1, "platform": {"os": "linux", "architecture": "amd64"}},
+				{"digest": "sha256:bbbb", "size": 1, "platform": {"os": "linux", "architecture": "arm", "variant": "v7"}}
+			]
+		}"#;
+		let refs = References::parse(manifest).unwrap();
+		assert_eq!(refs.blobs().count(), 0);
+		assert_eq!(refs.children(&[]).collect::<Vec<_>>(), vec!["sha256:aaaa", "sha256:bbbb"]);
+		assert_eq!(refs.children(&["linux/amd64".parse().unwrap()]).collect::<Vec<_>>(), vec!["sha256:aaaa"]);
+		assert_eq!(refs.children(&["linux/arm".parse().unwrap()]).collect::<Vec<_>>(), vec!["sha256:bbbb"]);
+		assert_eq!(refs.children(&["linux/arm/v6".parse().unwrap()]).count(), 0);
+	}
+
+	#[test]
+	fn filter_index_by_platform() {
+		let manifest = br#"{
+			"schemaVersion": 2,
+			"mediaType": "application/vnd.oci.image.index.v1+json",
+			"manifests": [
+				{"digest": "sha256:aaaa", "size": 1, "platform": {"os": "linux", "architecture": "amd64"}},
+				{"digest": "sha256:bbbb", "size": 1, "platform": {"os": "linux", "architecture": "arm", "variant": "v7"}},
+				{"digest": "sha256:cccc", "size": 1, "platform": {"os": "linux", "architecture": "arm64", "variant": "v8"}}
+			]
+		}"#;
+		let platforms = ["linux/amd64".parse().unwrap(), "linux/arm64".parse().unwrap()];
+		let filtered = filter_index(manifest, &platforms).unwrap().unwrap();
+		let refs = References::parse(&filtered).unwrap();
+		assert_eq!(refs.children(&[]).collect::<Vec<_>>(), vec!["sha256:aaaa", "sha256:cccc"]);
+		assert_eq!(References::parse(manifest).unwrap().excluded_children(&platforms).collect::<Vec<_>>(), vec!["sha256:bbbb"]);
+		assert!(filter_index(&filtered, &platforms).unwrap().is_none());
+		assert!(filter_index(br#"{"schemaVersion": 2, "layers": []}"#, &platforms).unwrap().is_none());
+	}
+
+	#[test]
+	fn parse_schema1() {
+		let manifest = br#"{"schemaVersion": 1, "fsLayers": [{"blobSum": "sha256:aaaa"}, {"blobSum": "sha256:bbbb"}]}"#;
+		let refs = References::parse(manifest).unwrap();
+		assert_eq!(refs.blobs().collect::<Vec<_>>(), vec!["sha256:aaaa", "sha256:bbbb"]);
+	}
+}
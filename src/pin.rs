@@ -0,0 +1,200 @@
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use bytes::Bytes;
+use compact_str::CompactString;
+use futures::stream::TryStreamExt;
+use serde_with::DeserializeFromStr;
+use serde_with::SerializeDisplay;
+use tracing::warn;
+
+use crate::digest::Digest;
+use crate::image::ImageName;
+use crate::image::ImageReference;
+use crate::manifest::Platform;
+use crate::manifest::References;
+use crate::storage;
+use crate::storage::Origin;
+use crate::storage::Repository;
+
+#[derive(Debug, thiserror::Error)]
+#[error("Invalid pin '{0}'; expected <namespace>/<image>:<tag>, <namespace>/<image>@<digest>, or <digest>")]
+pub struct InvalidPin(pub String);
+
+/// Something that should never be aged out, garbage collected, or evicted
+#[derive(Clone, Debug, PartialEq, Eq, Hash, DeserializeFromStr, SerializeDisplay)]
+pub enum Pin {
+	/// A manifest, e.g. `docker.io/library/busybox:latest`; it and everything it references,
+	/// including the children of an index, are pinned
+	Image { namespace: CompactString, image: CompactString, reference: CompactString },
+	/// A manifest or blob digest, in any repository
+	Digest(Digest)
+}
+
+impl FromStr for Pin {
+	type Err = InvalidPin;
+
+	fn from_str(input: &str) -> Result<Self, Self::Err> {
+		let invalid = || InvalidPin(input.to_string());
+		if (!input.contains('/')) {
+			return match input.parse::<ImageReference>() {
+				Ok(ImageReference::Digest(digest)) => Ok(Self::Digest(digest)),
+				_ => Err(invalid())
+			};
+		}
+		let (name, reference) = match input.split_once('@') {
+			Some((name, digest)) => (name, digest),
+			None => match input.rsplit_once(':') {
+				// A colon before the last slash is a port number, not a tag
+				Some((name, tag)) if !tag.contains('/') => (name, tag),
+				_ => (input, "latest")
+			}
+		};
+		let (namespace, image) = name.split_once('/').ok_or_else(invalid)?;
+		let image = image.parse::<ImageName>().map_err(|_| invalid())?;
+		let reference = reference.parse::<ImageReference>().map_err(|_| invalid())?;
+		if (input.contains('@') && matches!(reference, ImageReference::Tag(_))) {
+			return Err(invalid());
+		}
+		Ok(Self::Image {
+			namespace: namespace.into(),
+			image: image.as_ref().into(),
+			reference: reference.to_str().into()
+		})
+	}
+}
+
+impl fmt::Display for Pin {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			// Tags can't contain a colon, so that means it's a digest
+			Self::Image { namespace, image, reference } if reference.contains(':') => write!(f, "{namespace}/{image}@{reference}"),
+			Self::Image { namespace, image, reference } => write!(f, "{namespace}/{image}:{reference}"),
+			Self::Digest(digest) => digest.fmt(f)
+		}
+	}
+}
+
+impl Pin {
+	fn storage_path(&self) -> String {
+		format!("pins/{self}")
+	}
+}
+
+/// Pins from configuration, plus those added at runtime, which are stored alongside the cache so
+/// that they're shared between replicas and survive restarts
+#[derive(Clone, Debug)]
+pub struct Pins {
+	configured: Arc<[Pin]>
+}
+
+impl Pins {
+	pub fn new(configured: Vec<Pin>) -> Self {
+		Self { configured: configured.into() }
+	}
+
+	pub fn is_configured(&self, pin: &Pin) -> bool {
+		self.configured.contains(pin)
+	}
+
+	pub async fn list(&self, repo: &Repository) -> Result<Vec<Pin>, storage::Error> {
+		let mut pins = self.configured.to_vec();
+		let mut stored = repo.list("pins/");
+		while let Some(obj) = stored.try_next().await? {
+			if let Some(pin) = obj.key.strip_prefix("pins/").and_then(|s| s.parse().ok()) {
+				if (!pins.contains(&pin)) {
+					pins.push(pin);
+				}
+			}
+		}
+		Ok(pins)
+	}
+
+	pub async fn add(&self, repo: &Repository, pin: &Pin) -> Result<(), storage::Error> {
+		let origin = match pin {
+			Pin::Image { namespace, image, .. } => Origin::new(namespace, image),
+			Pin::Digest(_) => Origin::new("", "")
+		};
+		repo.write(&pin.storage_path(), futures::stream::empty::<Result<Bytes, std::io::Error>>(), 0, &origin).await
+	}
+
+	pub async fn remove(&self, repo: &Repository, pin: &Pin) -> Result<(), storage::Error> {
+		repo.delete(&pin.storage_path()).await
+	}
+
+	/// The storage paths of every manifest and blob that's pinned, directly or by being referenced
+	/// by a pinned manifest.  Tags are resolved to whatever is currently cached for them.  Children
+	/// of indexes for platforms that their namespace doesn't care about aren't included.
+	pub async fn protected(&self, repo: &Repository, platforms: &HashMap<CompactString, Vec<Platform>>) -> Result<HashSet<String>, storage::Error> {
+		let mut protected = HashSet::new();
+		let mut queue = Vec::new();
+		let mut digests = HashSet::new();
+		for pin in self.list(repo).await? {
+			match pin {
+				Pin::Image { namespace, image, reference } => queue.push(storage::manifest_path(&namespace, &image, &reference)),
+				Pin::Digest(digest) => {
+					protected.insert(storage::blob_path(&digest));
+					digests.insert(digest.to_string());
+				}
+			};
+		}
+		if (!digests.is_empty()) {
+			let mut manifests = repo.list("manifests/");
+			while let Some(obj) = manifests.try_next().await? {
+				if (obj.key.rsplit_once('/').map_or(false, |(_, reference)| digests.contains(reference))) {
+					queue.push(obj.key);
+				}
+			}
+		}
+
+		while let Some(path) = queue.pop() {
+			if (!protected.insert(path.clone())) {
+				continue;
+			}
+			let refs = match References::read(repo, &path).await {
+				Ok(Some(v)) => v,
+				Ok(None) => continue,
+				// It can't be aged out while it's pinned, so failing here would stop cleanup for good;
+				// garbage collection won't run while it's there either
+				Err(error @ storage::Error::InvalidManifest(..)) => {
+					warn!(%error, "Can't protect what a pinned manifest references");
+					continue;
+				},
+				Err(e) => return Err(e)
+			};
+			// Children of an index are cached alongside it, in the same repository
+			let wanted = storage::manifest_namespace(&path).and_then(|ns| platforms.get(ns)).map_or(&[][..], Vec::as_slice);
+			if let Some((dir, _)) = path.rsplit_once('/') {
+				queue.extend(refs.children(wanted).map(|digest| format!("{dir}/{digest}")));
+			}
+			protected.extend(refs.blob_paths(&path));
+		}
+		Ok(protected)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn parse_pins() {
+		let digest = "sha256:6864e61916f58174557076c34e7122753331cf28077edb0f23e1fb5419dd6acd";
+		let by_digest = format!("quay.io/prometheus/node-exporter@{digest}");
+		for (input, expected) in [
+			("docker.io/library/busybox:1.36", "docker.io/library/busybox:1.36"),
+			("docker.io/library/busybox", "docker.io/library/busybox:latest"),
+			("localhost:5000/myimage", "localhost:5000/myimage:latest"),
+			(by_digest.as_str(), by_digest.as_str()),
+			(digest, digest)
+		] {
+			assert_eq!(input.parse::<Pin>().unwrap().to_string(), expected);
+		}
+		assert!("busybox:latest".parse::<Pin>().is_err());
+		assert!("sha256:abcd".parse::<Pin>().is_err());
+		assert!("docker.io/library/busybox@latest".parse::<Pin>().is_err());
+	}
+}
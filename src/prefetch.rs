@@ -0,0 +1,299 @@
+use core::future;
+use core::time::Duration;
+
+use actix_web::rt;
+use actix_web::web;
+use camino::Utf8PathBuf;
+use clap::Parser;
+use compact_str::CompactString;
+use futures::stream::StreamExt;
+use futures::stream::TryStreamExt;
+use once_cell::sync::Lazy;
+use prometheus::register_int_counter_vec;
+use prometheus::register_int_gauge;
+use prometheus::IntCounterVec;
+use prometheus::IntGauge;
+use time::OffsetDateTime;
+use time::Time;
+use tokio::sync::Semaphore;
+use tracing::error;
+use tracing::info;
+use tracing::warn;
+
+use crate::api;
+use crate::api::error::Error;
+use crate::api::RequestConfig;
+use crate::image::ImageReference;
+use crate::manifest::Platform;
+use crate::manifest::References;
+use crate::storage;
+
+#[derive(Clone, Debug, Parser)]
+pub struct PrefetchConfig {
+	/// A file listing images to pull into the cache, one per line as "<namespace> <image> <tag>";
+	/// blank lines and lines starting with '#' are ignored.  The file is re-read on every run.
+	#[clap(env, long)]
+	prefetch_list: Option<Utf8PathBuf>,
+	/// If set, the images in --prefetch-list will be pulled every day at this time, given in UTC as
+	/// HH:MM
+	#[clap(env, long, value_parser = parse_time_of_day)]
+	prefetch_at: Option<Time>,
+	/// Maximum number of manifests and blobs to pull at once while prefetching
+	#[clap(env, long, default_value_t = 4)]
+	prefetch_concurrency: usize,
+	/// Only prefetch these platforms from multi-platform images, e.g. "linux/amd64,linux/arm64/v8";
+	/// if unset, all platforms will be prefetched.  Namespaces configured with their own platforms
+	/// use those instead.
+	#[clap(env, long, value_delimiter = ',')]
+	prefetch_platforms: Vec<Platform>
+}
+
+fn parse_time_of_day(input: &str) -> Result<Time, String> {
+	let (hour, minute) = input.split_once(':').ok_or_else(|| format!("Expected HH:MM, got '{input}'"))?;
+	let hour = hour.parse().map_err(|_| format!("Invalid hour '{hour}'"))?;
+	let minute = minute.parse().map_err(|_| format!("Invalid minute '{minute}'"))?;
+	Time::from_hms(hour, minute, 0).map_err(|e| e.to_string())
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ListError {
+	#[error("No image list configured; set --prefetch-list")]
+	NotConfigured,
+	#[error("Error reading image list: {0}")]
+	Io(#[from] std::io::Error),
+	#[error("Line {0} of image list is invalid; expected '<namespace> <image> <tag>'")]
+	Invalid(usize)
+}
+
+#[derive(Debug)]
+pub struct ListedImage {
+	pub namespace: CompactString,
+	pub image: CompactString,
+	pub reference: ImageReference
+}
+
+/// Parses an image list in the format of `testdata/images.txt`
+pub fn parse_list(list: &str) -> Result<Vec<ListedImage>, ListError> {
+	let mut images = Vec::new();
+	for (i, line) in list.lines().enumerate() {
+		let line = line.trim();
+		if (line.is_empty() || line.starts_with('#')) {
+			continue;
+		}
+		let mut fields = line.split_whitespace();
+		let (Some(namespace), Some(image), Some(reference), None) = (fields.next(), fields.next(), fields.next(), fields.next()) else {
+			return Err(ListError::Invalid(i + 1));
+		};
+		let reference = reference.parse().map_err(|_| ListError::Invalid(i + 1))?;
+		images.push(ListedImage { namespace: namespace.into(), image: image.into(), reference });
+	}
+	Ok(images)
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Report {
+	/// Number of images listed
+	pub images: usize,
+	/// Number of images that couldn't be completely prefetched
+	pub failed: usize,
+	/// Number of manifests read, including the children of indexes
+	pub manifests: usize,
+	/// Number of blobs read
+	pub blobs: usize,
+	/// Total size of blobs that weren't already cached
+	pub bytes: u64
+}
+
+impl PrefetchConfig {
+	/// Pulls every image in the list, along with everything it references, into the cache
+	pub async fn run(&self, config: &web::Data<RequestConfig>) -> Result<Report, ListError> {
+		static PENDING: Lazy<IntGauge> = Lazy::new(|| register_int_gauge!("prefetch_images_pending", "Number of images remaining in the current prefetch run").unwrap());
+		static FAILED: Lazy<IntCounterVec> = Lazy::new(|| register_int_counter_vec!("prefetch_errors", "Number of images that couldn't be prefetched", &["namespace"]).unwrap());
+
+		let path = self.prefetch_list.as_ref().ok_or(ListError::NotConfigured)?;
+		let images = parse_list(&tokio::fs::read_to_string(path).await?)?;
+		let permits = &Semaphore::new(self.prefetch_concurrency.max(1));
+		let platforms = self.prefetch_platforms.as_slice();
+		let mut report = Report { images: images.len(), ..Report::default() };
+		PENDING.set(images.len().try_into().unwrap_or(i64::MAX));
+
+		let mut results = futures::stream::iter(images.iter())
+			.map(|listed| async move { (listed, prefetch_image(config, listed, platforms, permits).await) })
+			.buffer_unordered(usize::MAX);
+		while let Some((listed, result)) = results.next().await {
+			PENDING.dec();
+			match result {
+				Ok(image) => {
+					report.manifests += image.manifests;
+					report.blobs += image.blobs;
+					report.bytes += image.bytes;
+				},
+				Err(error) => {
+					FAILED.with_label_values(&[listed.namespace.as_str()]).inc();
+					error!(namespace = %listed.namespace, image = %listed.image, reference = %listed.reference, %error, "Error prefetching image");
+					report.failed += 1;
+				}
+			};
+		}
+		info!(images = report.images, failed = report.failed, manifests = report.manifests, blobs = report.blobs, bytes = report.bytes, "Prefetch complete");
+		Ok(report)
+	}
+
+	/// Runs [`run`](Self::run) every day at `--prefetch-at`, if it's set; otherwise, returns
+	/// immediately
+	pub async fn schedule(&self, config: web::Data<RequestConfig>) {
+		let Some(at) = self.prefetch_at else {
+			return;
+		};
+		loop {
+			tokio::time::sleep(until(at, OffsetDateTime::now_utc())).await;
+			if let Err(error) = self.run(&config).await {
+				error!(%error, "Error prefetching images");
+			}
+		}
+	}
+}
+
+/// How long from `now` until the next time it's `at` o'clock
+fn until(at: Time, now: OffsetDateTime) -> Duration {
+	let mut next = now.replace_time(at);
+	if (next <= now) {
+		next += time::Duration::DAY;
+	}
+	(next - now).try_into().unwrap_or_default()
+}
+
+/// Pulls an image's manifest, its children if it's an index, and all the blobs they reference
+/// through the same paths as client requests.  `permits` bounds how many are in flight at once.
+/// `platforms` applies unless the image's namespace is configured with its own.
+async fn prefetch_image(config: &web::Data<RequestConfig>, listed: &ListedImage, platforms: &[Platform], permits: &Semaphore) -> Result<Report, Error> {
+	let platforms = config.platforms(listed.namespace.as_str(), platforms).await?;
+	let report = prefetch_tree(config, listed.namespace.as_str(), listed.image.as_str(), vec![listed.reference.clone()], Vec::new(), &platforms, permits).await?;
+	Ok(Report { images: 1, ..report })
+}
+
+/// Pulls the manifests in `queue`, the children of any of them that are indexes, and all the
+/// blobs they reference, plus the blobs in `blobs`
+async fn prefetch_tree(config: &web::Data<RequestConfig>, namespace: &str, image: &str, mut queue: Vec<ImageReference>, mut blobs: Vec<String>, platforms: &[Platform], permits: &Semaphore) -> Result<Report, Error> {
+	static MANIFESTS: Lazy<IntCounterVec> = Lazy::new(|| register_int_counter_vec!("prefetch_manifests", "Number of manifests read by prefetching", &["namespace"]).unwrap());
+	static BLOBS: Lazy<IntCounterVec> = Lazy::new(|| register_int_counter_vec!("prefetch_blobs", "Number of blobs read by prefetching", &["namespace"]).unwrap());
+	static BYTES: Lazy<IntCounterVec> = Lazy::new(|| register_int_counter_vec!("prefetch_bytes", "Total size of blobs pulled from upstream by prefetching", &["namespace"]).unwrap());
+
+	let mut report = Report::default();
+	while let Some(reference) = queue.pop() {
+		let manifest = {
+			let _permit = permits.acquire().await.unwrap();
+			let storage_path = storage::manifest_path(namespace, image, &reference.to_str());
+			api::fetch_manifest(config, namespace, image, &reference, &storage_path).await?.0
+		};
+		MANIFESTS.with_label_values(&[namespace]).inc();
+		report.manifests += 1;
+		let refs = References::parse(manifest.manifest.as_ref())?;
+		queue.extend(children(&refs, platforms, namespace, image));
+		blobs.extend(refs.blobs().map(String::from));
+	}
+	blobs.sort_unstable();
+	blobs.dedup();
+
+	let sizes = futures::stream::iter(blobs)
+		.map(|digest| async move {
+			let _permit = permits.acquire().await.unwrap();
+			let blob = api::fetch_blob(config, namespace, image, &api::parse_digest(&digest)?).await?;
+			// A cache miss is written to storage as it's read, so it has to be read to the end
+			let bytes = match blob.cache_status.is_hit() {
+				true => 0,
+				false => blob.stream.try_fold(0, |n, chunk| future::ready(Ok(n + chunk.len() as u64))).await?
+			};
+			// Writing happens in the background, which the prefetch subcommand would otherwise exit
+			// in the middle of
+			blob.written.await?;
+			BLOBS.with_label_values(&[namespace]).inc();
+			BYTES.with_label_values(&[namespace]).inc_by(bytes);
+			Ok::<_, Error>(bytes)
+		})
+		.buffer_unordered(usize::MAX)
+		.try_collect::<Vec<_>>()
+		.await?;
+	report.blobs = sizes.len();
+	report.bytes = sizes.into_iter().sum();
+	Ok(report)
+}
+
+fn children<'a>(refs: &'a References, platforms: &'a [Platform], namespace: &'a str, image: &'a str) -> impl Iterator<Item = ImageReference> + 'a {
+	refs.children(platforms).filter_map(move |child| match child.parse() {
+		Ok(v) => Some(v),
+		Err(error) => {
+			warn!(namespace, image, %error, "Skipping child manifest with unsupported digest");
+			None
+		}
+	})
+}
+
+/// Settings and limits shared by every prefetch triggered by a client request
+pub struct Background {
+	platforms: Vec<Platform>,
+	permits: Semaphore
+}
+
+impl PrefetchConfig {
+	pub fn background(&self) -> Background {
+		Background {
+			platforms: self.prefetch_platforms.clone(),
+			permits: Semaphore::new(self.prefetch_concurrency.max(1))
+		}
+	}
+}
+
+/// Starts pulling everything referenced by a manifest that was just pulled from upstream - child
+/// manifests and blobs - so that it's already cached by the time the client asks for it
+pub fn spawn_referenced(config: &web::Data<RequestConfig>, namespace: &str, image: &str, manifest: &[u8]) {
+	let refs = match References::parse(manifest) {
+		Ok(v) => v,
+		Err(error) => {
+			warn!(namespace, image, %error, "Unable to parse manifest for prefetching");
+			return;
+		}
+	};
+	let config = config.clone();
+	let (namespace, image) = (CompactString::from(namespace), CompactString::from(image));
+	rt::spawn(async move {
+		let background = config.background_prefetch();
+		let platforms = match config.platforms(&namespace, &background.platforms).await {
+			Ok(v) => v,
+			Err(error) => {
+				warn!(%namespace, %image, %error, "Error prefetching referenced content");
+				return;
+			}
+		};
+		let queue = children(&refs, &platforms, &namespace, &image).collect();
+		let blobs = refs.blobs().map(String::from).collect();
+		match prefetch_tree(&config, &namespace, &image, queue, blobs, &platforms, &background.permits).await {
+			Ok(report) => info!(%namespace, %image, manifests = report.manifests, blobs = report.blobs, bytes = report.bytes, "Prefetched referenced content"),
+			Err(error) => warn!(%namespace, %image, %error, "Error prefetching referenced content")
+		};
+	});
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn parse_image_list() {
+		let list = "#   ns             repository            tag\ndocker.io\ttarampampam/webhook-tester\t0.4.2\n\n  quay.io prometheus/node-exporter latest\n";
+		let images = parse_list(list).unwrap();
+		assert_eq!(images.len(), 2);
+		assert_eq!(images[1].namespace, "quay.io");
+		assert_eq!(images[1].image, "prometheus/node-exporter");
+		assert_eq!(images[1].reference.to_string(), "latest");
+		assert!(matches!(parse_list("docker.io library/busybox\n"), Err(ListError::Invalid(1))));
+	}
+
+	#[test]
+	fn time_until() {
+		let now = OffsetDateTime::UNIX_EPOCH.replace_time(Time::from_hms(12, 0, 0).unwrap());
+		assert_eq!(until(Time::from_hms(13, 30, 0).unwrap(), now), Duration::from_secs(90 * 60));
+		assert_eq!(until(Time::from_hms(12, 0, 0).unwrap(), now), Duration::from_secs(24 * 60 * 60));
+		assert_eq!(until(parse_time_of_day("06:00").unwrap(), now), Duration::from_secs(18 * 60 * 60));
+	}
+}
@@ -0,0 +1,165 @@
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::fmt;
+
+use compact_str::CompactString;
+use futures::stream::TryStreamExt;
+use tracing::error;
+use tracing::info;
+use tracing::warn;
+
+use crate::gc;
+use crate::jobs::JobHandle;
+use crate::manifest::Platform;
+use crate::manifest::References;
+use crate::storage;
+use crate::storage::Repository;
+
+/// What to purge from the cache
+#[derive(Clone, Debug)]
+pub enum Target {
+	/// Every manifest cached from a namespace
+	Namespace(String),
+	/// Manifests cached from a repository; if `tags` is set, only those whose reference matches
+	/// it, as a glob supporting `*` and `?`
+	Repository { namespace: String, repository: String, tags: Option<String> }
+}
+
+impl fmt::Display for Target {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Self::Namespace(namespace) => write!(f, "purge namespace {namespace}"),
+			Self::Repository { namespace, repository, tags: None } => write!(f, "purge repository {namespace}/{repository}"),
+			Self::Repository { namespace, repository, tags: Some(tags) } => write!(f, "purge tags {tags} from repository {namespace}/{repository}")
+		}
+	}
+}
+
+/// What to consider when deleting blobs that purged manifests referenced
+pub struct Cascade<'a> {
+	/// Storage paths of blobs that mustn't be deleted
+	pub pinned: &'a HashSet<String>,
+	pub platforms: &'a HashMap<CompactString, Vec<Platform>>
+}
+
+/// Deletes the manifests in `target`, counting what's deleted in `job`.  With `cascade`, blobs
+/// that those manifests referenced are then deleted too, unless some other cached manifest still
+/// references them or they're pinned.
+pub async fn purge(repo: &Repository, target: &Target, cascade: Option<Cascade<'_>>, job: &JobHandle) -> Result<(), storage::Error> {
+	let mut candidates = HashSet::new();
+	match target {
+		Target::Namespace(namespace) => {
+			let prefix = format!("manifests/{namespace}/");
+			if (cascade.is_some()) {
+				let mut manifests = repo.list(&prefix);
+				while let Some(obj) = manifests.try_next().await? {
+					candidates.extend(referenced_blobs(repo, &obj.key).await?);
+				}
+			}
+			let count = repo.delete_prefix(&prefix).await?;
+			job.add("manifests", count.try_into().unwrap_or_default());
+		},
+		Target::Repository { namespace, repository, tags } => {
+			let prefix = storage::manifest_path(namespace, repository, "");
+			// Only direct children; anything deeper belongs to a nested repository
+			let mut paths = Vec::new();
+			let mut manifests = repo.list(&prefix);
+			while let Some(obj) = manifests.try_next().await? {
+				match obj.key.strip_prefix(prefix.as_str()) {
+					Some(reference) if !reference.contains('/') && tags.as_deref().map_or(true, |tags| glob_match(tags, reference)) => paths.push(obj.key),
+					_ => ()
+				};
+			}
+			for path in paths {
+				if (cascade.is_some()) {
+					candidates.extend(referenced_blobs(repo, &path).await?);
+				}
+				match repo.delete(&path).await {
+					Ok(_) => job.add("manifests", 1),
+					Err(e) if e.is_not_found() => (),
+					Err(e) => return Err(e)
+				};
+			}
+		}
+	};
+
+	let Some(cascade) = cascade else {
+		return Ok(());
+	};
+	let (marked, _) = gc::mark(repo, cascade.platforms).await?;
+	for path in candidates {
+		if (marked.contains(&path) || cascade.pinned.contains(&path)) {
+			continue;
+		}
+		match repo.delete(&path).await {
+			Ok(_) => {
+				info!(path, "Deleted blob no longer referenced after purge");
+				job.add("blobs", 1);
+			},
+			Err(e) if e.is_not_found() => (),
+			Err(error) => error!(path, %error, "Error deleting blob no longer referenced after purge")
+		};
+	}
+	Ok(())
+}
+
+/// Storage paths of the blobs that the manifest at `path` references.  If it can't be parsed,
+/// they're left for garbage collection, which won't run until it's gone.
+async fn referenced_blobs(repo: &Repository, path: &str) -> Result<Vec<String>, storage::Error> {
+	match References::read(repo, path).await {
+		Ok(Some(refs)) => Ok(refs.blob_paths(path).collect()),
+		Ok(None) => Ok(Vec::new()),
+		Err(error @ storage::Error::InvalidManifest(..)) => {
+			warn!(%error, "Not deleting the blobs of a manifest that can't be parsed");
+			Ok(Vec::new())
+		},
+		Err(e) => Err(e)
+	}
+}
+
+/// Matches `input` against `pattern`, where `*` matches any run of characters and `?` matches any
+/// single character
+pub fn glob_match(pattern: &str, input: &str) -> bool {
+	let (pattern, input) = (pattern.chars().collect::<Vec<_>>(), input.chars().collect::<Vec<_>>());
+	let (mut p, mut i) = (0, 0);
+	// Where to resume if the most recent `*` needs to match more
+	let mut backtrack = None;
+	while (i < input.len()) {
+		match pattern.get(p) {
+			Some('*') => {
+				backtrack = Some((p, i));
+				p += 1;
+			},
+			Some(&c) if c == '?' || c == input[i] => {
+				p += 1;
+				i += 1;
+			},
+			_ => match backtrack {
+				Some((star, matched)) => {
+					backtrack = Some((star, matched + 1));
+					p = star + 1;
+					i = matched + 1;
+				},
+				None => return false
+			}
+		};
+	}
+	pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn globs() {
+		assert!(glob_match("*", "latest"));
+		assert!(glob_match("v1.*", "v1.2.3"));
+		assert!(glob_match("v?.2*", "v1.2.3"));
+		assert!(glob_match("*-alpine", "3.19-alpine"));
+		assert!(glob_match("a*b*c", "aXbYbZc"));
+		assert!(!glob_match("v1.*", "v2.0"));
+		assert!(!glob_match("v?", "v10"));
+		assert!(!glob_match("latest", "latest-alpine"));
+	}
+}
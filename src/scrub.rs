@@ -0,0 +1,224 @@
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::time::Instant;
+use std::time::SystemTime;
+
+use bytesize::ByteSize;
+use clap::Parser;
+use futures::stream::StreamExt;
+use futures::stream::TryStreamExt;
+use humantime::Duration;
+use once_cell::sync::Lazy;
+use prometheus::register_int_counter;
+use prometheus::register_int_gauge;
+use prometheus::IntCounter;
+use prometheus::IntGauge;
+use tracing::error;
+use tracing::info;
+use tracing::warn;
+
+use crate::api::stream;
+use crate::digest::Digest;
+use crate::storage;
+use crate::storage::Origin;
+use crate::storage::Repository;
+
+#[derive(Clone, Debug, Parser)]
+pub struct ScrubConfig {
+	/// If enabled, every cached blob will periodically be re-read and checked against the digest
+	/// it's stored under, so that corruption is found before a client pulls it
+	#[clap(env = "SCRUB", long = "scrub", default_value_t = false)]
+	enabled: bool,
+	/// How long to wait between scrub passes
+	#[clap(env, long, default_value = "1d")]
+	scrub_interval: Duration,
+	/// Maximum rate at which blobs will be read while scrubbing, so that it doesn't compete with
+	/// clients for storage bandwidth; 0 for no limit
+	#[clap(env, long, default_value = "10MiB")]
+	scrub_bytes_per_second: ByteSize,
+	/// Copy corrupt blobs under quarantine/ for inspection before deleting them.  Nothing cleans
+	/// up quarantine/; that's left to the operator.
+	#[clap(env, long, default_value_t = false)]
+	scrub_quarantine: bool
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Report {
+	/// Number of blobs checked
+	pub blobs: usize,
+	/// Total size of blobs checked
+	pub bytes: u64,
+	/// Number of blobs that didn't match their digest and were removed
+	pub corrupt: usize
+}
+
+impl ScrubConfig {
+	pub async fn schedule(&self, repo: Repository) {
+		if (!self.enabled) {
+			return;
+		}
+		loop {
+			tokio::time::sleep(*self.scrub_interval).await;
+			match self.run(&repo).await {
+				Ok(report) => info!(blobs = report.blobs, bytes = report.bytes, corrupt = report.corrupt, "Scrub complete"),
+				Err(error) => error!(%error, "Error scrubbing blobs")
+			};
+		}
+	}
+
+	/// Reads every blob, removing those that don't match the digest they're stored under.  Each
+	/// tier of tiered storage is scrubbed separately, since either copy of a blob may be corrupt.
+	pub async fn run(&self, repo: &Repository) -> Result<Report, storage::Error> {
+		static PASS_BYTES: Lazy<IntGauge> = Lazy::new(|| register_int_gauge!("scrub_pass_checked_bytes", "Total size of blobs checked so far in the current scrub pass").unwrap());
+		static LAST_COMPLETE: Lazy<IntGauge> = Lazy::new(|| register_int_gauge!("scrub_last_complete_timestamp_seconds", "When the last scrub pass finished").unwrap());
+
+		let throttle = Throttle::new(self.scrub_bytes_per_second.as_u64());
+		let mut report = Report::default();
+		PASS_BYTES.set(0);
+		for (tier, repo) in repo.tiers() {
+			self.scrub_tier(&repo, tier, &throttle, &mut report, &PASS_BYTES).await?;
+		}
+		LAST_COMPLETE.set(SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).map_or(0, |d| d.as_secs().try_into().unwrap_or(i64::MAX)));
+		Ok(report)
+	}
+
+	async fn scrub_tier(&self, repo: &Repository, tier: &str, throttle: &Throttle, report: &mut Report, pass_bytes: &IntGauge) -> Result<(), storage::Error> {
+		static CHECKED_COUNT: Lazy<IntCounter> = Lazy::new(|| register_int_counter!("scrub_checked_blobs", "Number of blobs checked by scrubbing").unwrap());
+		static CHECKED_BYTES: Lazy<IntCounter> = Lazy::new(|| register_int_counter!("scrub_checked_bytes", "Total size of blobs checked by scrubbing").unwrap());
+		static CORRUPT_COUNT: Lazy<IntCounter> = Lazy::new(|| register_int_counter!("scrub_corrupt_blobs", "Number of blobs found by scrubbing not to match their digest").unwrap());
+
+		let mut blobs = repo.list("blobs/");
+		while let Some(obj) = blobs.try_next().await? {
+			let Some(expected) = storage::blob_digest(&obj.key).and_then(|digest| digest.parse::<Digest>().ok()) else {
+				warn!(tier, path = obj.key, "Skipping blob stored under an unsupported digest");
+				continue;
+			};
+			let stream = match repo.read_unrecorded(&obj.key).await {
+				Ok(v) => v,
+				// Deleted since it was listed
+				Err(e) if e.is_not_found() => continue,
+				Err(error) => {
+					error!(tier, path = obj.key, %error, "Error reading blob to scrub");
+					continue;
+				}
+			};
+			let paced = stream.into_inner().then(|chunk| async move {
+				if let Ok(chunk) = chunk.as_ref() {
+					throttle.consume(chunk.len() as u64).await;
+				}
+				chunk
+			});
+			let actual = match stream::hash(Box::pin(paced), expected.algorithm()).await {
+				Ok(v) => v,
+				Err(error) => {
+					error!(tier, path = obj.key, %error, "Error reading blob to scrub");
+					continue;
+				}
+			};
+			CHECKED_COUNT.inc();
+			CHECKED_BYTES.inc_by(obj.size);
+			pass_bytes.add(obj.size.try_into().unwrap_or(i64::MAX));
+			report.blobs += 1;
+			report.bytes += obj.size;
+			if (actual == expected) {
+				continue;
+			}
+
+			error!(tier, path = obj.key, %expected, %actual, "Blob doesn't match its digest");
+			CORRUPT_COUNT.inc();
+			report.corrupt += 1;
+			if (self.scrub_quarantine) {
+				if let Err(error) = quarantine(repo, &obj.key).await {
+					error!(tier, path = obj.key, %error, "Error quarantining corrupt blob; leaving it in place");
+					continue;
+				}
+			}
+			if let Err(error) = repo.delete(&obj.key).await {
+				error!(tier, path = obj.key, %error, "Error deleting corrupt blob");
+			}
+		}
+		Ok(())
+	}
+}
+
+/// Copies a blob under `quarantine/`, which nothing else reads or cleans up
+async fn quarantine(repo: &Repository, path: &str) -> Result<(), storage::Error> {
+	let stream = repo.read_unrecorded(path).await?;
+	let length = stream.length().try_into().unwrap_or(i64::MAX);
+	repo.write(&format!("quarantine/{path}"), stream.into_inner(), length, &Origin::new("", "")).await
+}
+
+/// Paces reads to an average of `rate` bytes per second
+struct Throttle {
+	rate: u64,
+	started: Instant,
+	consumed: AtomicU64
+}
+
+impl Throttle {
+	fn new(rate: u64) -> Self {
+		Self { rate, started: Instant::now(), consumed: AtomicU64::new(0) }
+	}
+
+	async fn consume(&self, bytes: u64) {
+		if (self.rate == 0) {
+			return;
+		}
+		let consumed = self.consumed.fetch_add(bytes, Ordering::Relaxed) + bytes;
+		let due = core::time::Duration::from_secs_f64(consumed as f64 / self.rate as f64);
+		if let Some(wait) = due.checked_sub(self.started.elapsed()) {
+			tokio::time::sleep(wait).await;
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use bytes::Bytes;
+
+	use super::*;
+	use crate::digest::Algorithm;
+	use crate::storage::filesystem;
+	use crate::storage::StorageConfig;
+
+	#[actix_web::test]
+	async fn throttle() {
+		let unlimited = Throttle::new(0);
+		unlimited.consume(u64::MAX / 2).await;
+		assert!(unlimited.started.elapsed() < core::time::Duration::from_millis(100));
+
+		let throttle = Throttle::new(1000);
+		throttle.consume(100).await;
+		throttle.consume(100).await;
+		assert!(throttle.started.elapsed() >= core::time::Duration::from_millis(200));
+	}
+
+	#[actix_web::test]
+	async fn corrupt_blob() {
+		let root = std::env::temp_dir().join(format!("oci-registry-scrub-{}", std::process::id()));
+		let repo = StorageConfig::Filesystem(filesystem::Config::parse_from(["filesystem", "--root", root.to_str().unwrap()]))
+			.repository()
+			.unwrap();
+		let write = |path: String, body: &'static [u8]| {
+			let repo = repo.clone();
+			async move {
+				repo.write(&path, futures::stream::iter([Ok::<_, std::io::Error>(Bytes::from_static(body))]), body.len().try_into().unwrap(), &Origin::new("", ""))
+					.await
+					.unwrap();
+			}
+		};
+		let good = storage::blob_path(&Algorithm::Sha256.digest(b"good"));
+		let corrupt = storage::blob_path(&Algorithm::Sha256.digest(b"original"));
+		write(good.clone(), b"good").await;
+		write(corrupt.clone(), b"bit rot").await;
+
+		let config = ScrubConfig::parse_from(["scrub", "--scrub", "--scrub-quarantine", "--scrub-bytes-per-second", "0"]);
+		let report = config.run(&repo).await.unwrap();
+		assert_eq!((report.blobs, report.bytes, report.corrupt), (2, 11, 1));
+		assert!(repo.read_unrecorded(&good).await.is_ok());
+		assert!(repo.read_unrecorded(&corrupt).await.unwrap_err().is_not_found());
+		let quarantined = repo.read_unrecorded(&format!("quarantine/{corrupt}")).await.unwrap();
+		assert_eq!(quarantined.into_inner().try_collect::<Vec<_>>().await.unwrap().concat(), b"bit rot");
+		std::fs::remove_dir_all(root).unwrap();
+	}
+}
@@ -1,58 +1,85 @@
+use core::future::Future;
 use core::time::Duration;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::time::Instant;
 use std::time::SystemTime;
 
 use actix_web::body::SizedStream;
 use bytes::Bytes;
 use clap::Subcommand;
 use compact_str::format_compact;
+use compact_str::CompactString;
 use dkregistry::mediatypes::MediaTypes;
 use futures::stream::BoxStream;
+use futures::stream::StreamExt;
 use futures::stream::TryStream;
 use futures::stream::TryStreamExt;
+use once_cell::sync::Lazy;
+use prometheus::register_histogram_vec;
+use prometheus::register_int_counter_vec;
+use prometheus::HistogramVec;
+use prometheus::IntCounterVec;
 use serde::Deserialize;
 use serde::Serialize;
+use tracing::error;
+use tracing::info;
+use tracing::warn;
+
+use crate::digest::Digest;
 
 mod error;
 pub mod filesystem;
 pub mod s3;
+pub mod tiered;
 
 pub use error::Error;
 
 #[derive(Clone, Debug, Subcommand)]
 pub enum StorageConfig {
 	S3(s3::Config),
-	Filesystem(filesystem::Config)
+	Filesystem(filesystem::Config),
+	/// A local filesystem cache, bounded in size, in front of S3
+	Tiered(tiered::Config)
 }
 
 impl StorageConfig {
-	pub fn repository(&self) -> Repository {
-		match self {
-			Self::S3(config) => Repository::S3(config.repository()),
-			Self::Filesystem(config) => Repository::Filesystem(config.repository())
-		}
+	/// Fails if the settings are invalid, or credentials can't be set up from them
+	pub fn repository(&self) -> Result<Repository, Error> {
+		Ok(match self {
+			Self::S3(config) => Repository::S3(config.repository()?),
+			Self::Filesystem(config) => Repository::Filesystem(config.repository()),
+			Self::Tiered(config) => Repository::Tiered(config.repository()?)
+		})
 	}
 }
 
 #[derive(Clone)]
 pub enum Repository {
 	S3(s3::Repository),
-	Filesystem(filesystem::Repository)
+	Filesystem(filesystem::Repository),
+	Tiered(tiered::Repository)
 }
 
 pub struct ReadStream {
 	length: u64,
+	modified: SystemTime,
 	inner: BoxStream<'static, Result<Bytes, std::io::Error>>
 }
 
 impl ReadStream {
-	pub fn new(length: u64, inner: BoxStream<'static, Result<Bytes, std::io::Error>>) -> Self {
-		Self { length, inner }
+	pub fn new(length: u64, modified: SystemTime, inner: BoxStream<'static, Result<Bytes, std::io::Error>>) -> Self {
+		Self { length, modified, inner }
 	}
 
 	pub fn length(&self) -> u64 {
 		self.length
 	}
 
+	pub fn modified(&self) -> SystemTime {
+		self.modified
+	}
+
 	pub fn into_inner(self) -> BoxStream<'static, Result<Bytes, std::io::Error>> {
 		self.inner
 	}
@@ -64,54 +91,352 @@ impl From<ReadStream> for SizedStream<BoxStream<'static, Result<Bytes, Box<dyn s
 	}
 }
 
+/// Where a stored object was pulled from.  Blobs are content-addressed and may be shared between
+/// repositories; this records the one that caused it to be cached.
+#[derive(Clone, Debug)]
+pub struct Origin {
+	pub namespace: CompactString,
+	pub repository: CompactString
+}
+
+impl Origin {
+	pub fn new(namespace: &str, repository: &str) -> Self {
+		Self { namespace: namespace.into(), repository: repository.into() }
+	}
+}
+
 impl Repository {
+	fn backend(&self) -> &'static str {
+		match self {
+			Self::S3(_) => "s3",
+			Self::Filesystem(_) => "filesystem",
+			Self::Tiered(_) => "tiered"
+		}
+	}
+
+	/// Records how long a storage operation took, and whether it failed, by backend.  An object
+	/// not being found isn't a failure; that's how cache misses are discovered.
+	async fn timed<T>(&self, operation: &'static str, f: impl Future<Output = Result<T, Error>>) -> Result<T, Error> {
+		static DURATION: Lazy<HistogramVec> = Lazy::new(|| {
+			register_histogram_vec!(
+				"storage_operation_duration_seconds",
+				"Time taken by storage operations; reads are timed until the object starts streaming, writes until it's completely written",
+				&["backend", "operation"]
+			)
+			.unwrap()
+		});
+		static ERRORS: Lazy<IntCounterVec> = Lazy::new(|| register_int_counter_vec!("storage_errors", "Number of storage operations that failed", &["backend", "operation"]).unwrap());
+
+		let start = Instant::now();
+		let result = f.await;
+		DURATION.with_label_values(&[self.backend(), operation]).observe(start.elapsed().as_secs_f64());
+		if let Err(e) = &result {
+			if (!e.is_not_found()) {
+				ERRORS.with_label_values(&[self.backend(), operation]).inc();
+			}
+		}
+		result
+	}
+
 	pub async fn read(&self, object: &str, invalidation: Duration) -> Result<ReadStream, Error> {
-		let result = match self {
-			Self::S3(r) => r.read(object, invalidation).await?,
-			Self::Filesystem(r) => r.read(object.into(), invalidation).await?
-		};
-		Ok(result)
+		self.timed("read", async {
+			let result = match self {
+				Self::S3(r) => r.read(object, invalidation).await?,
+				Self::Filesystem(r) => r.read(object.into(), invalidation).await?,
+				Self::Tiered(r) => r.read(object, invalidation).await?
+			};
+			Ok::<_, Error>(result)
+		})
+		.await
+	}
+
+	/// Reads an object regardless of its age, without counting it as being used:  nothing is
+	/// recorded for least-recently-used eviction, and nothing is copied into the local tier of
+	/// tiered storage.  For internal maintenance, rather than serving clients.
+	pub async fn read_unrecorded(&self, object: &str) -> Result<ReadStream, Error> {
+		match self {
+			Self::S3(r) => r.read_unrecorded(object).await,
+			Self::Filesystem(r) => r.read_unrecorded(object.into()).await,
+			Self::Tiered(r) => r.read_unrecorded(object).await
+		}
 	}
 
-	pub async fn write<S, E>(&self, object: &str, reader: S, length: i64) -> Result<(), Error>
+	/// Like [`Self::read_unrecorded`], but bypasses the local tier of tiered storage altogether, so
+	/// that what's read is what every replica sharing the storage sees
+	pub async fn read_shared(&self, object: &str) -> Result<ReadStream, Error> {
+		match self {
+			Self::S3(r) => r.read_unrecorded(object).await,
+			Self::Filesystem(r) => r.read_unrecorded(object.into()).await,
+			Self::Tiered(r) => r.read_shared(object).await
+		}
+	}
+
+	/// Returns a URL from which the object can be downloaded directly, bypassing this process, if
+	/// the backend supports it
+	pub async fn presigned_url(&self, object: &str, invalidation: Duration, expires_in: Duration) -> Result<Option<String>, Error> {
+		match self {
+			Self::S3(r) => r.presigned_url(object, invalidation, expires_in).await,
+			Self::Filesystem(_) => Ok(None),
+			Self::Tiered(r) => r.presigned_url(object, invalidation, expires_in).await
+		}
+	}
+
+	pub async fn write<S, E>(&self, object: &str, reader: S, length: i64, origin: &Origin) -> Result<(), Error>
 	where
 		S: TryStream<Ok = Bytes, Error = E> + Unpin + Send + 'static,
 		E: std::error::Error + From<std::io::Error> + Send + Sync + 'static,
 		Error: From<E>
 	{
-		#[allow(clippy::let_unit_value)] // Because it's likely that we will change the return type eventually, it'll require fewer changes, and it's harmless as-is.
-		let result = match self {
-			Self::S3(r) => r.write(object, reader, length).await?,
-			Self::Filesystem(r) => r.write(object.into(), reader).await?
-		};
-		Ok(result)
+		self.timed("write", async {
+			#[allow(clippy::let_unit_value)] // Because it's likely that we will change the return type eventually, it'll require fewer changes, and it's harmless as-is.
+			let result = match self {
+				Self::S3(r) => r.write(object, reader, length, origin).await?,
+				Self::Filesystem(r) => r.write(object.into(), reader).await?,
+				Self::Tiered(r) => r.write(object, reader, length, origin).await?
+			};
+			Ok::<_, Error>(result)
+		})
+		.await
 	}
 
 	pub async fn delete(&self, object: &str) -> Result<(), Error> {
+		self.timed("delete", async {
+			match self {
+				Self::S3(r) => r.delete(object).await?,
+				Self::Filesystem(r) => r.delete_object(object.into()).await?,
+				Self::Tiered(r) => r.delete(object).await?
+			};
+			Ok::<_, Error>(())
+		})
+		.await
+	}
+
+	/// Deletes every object whose path starts with `prefix`, which must end with a `/`, and
+	/// returns how many were deleted
+	pub async fn delete_prefix(&self, prefix: &str) -> Result<usize, Error> {
+		match self {
+			Self::S3(r) => r.delete_prefix(prefix).await,
+			Self::Filesystem(r) => r.delete_prefix(prefix.as_ref()).await,
+			Self::Tiered(r) => r.delete_prefix(prefix).await
+		}
+	}
+
+	/// Records that `origin` references the blob stored at `blob_path`, so that the blob is
+	/// retained for as long as that namespace's blob invalidation time requires
+	pub async fn add_reference(&self, blob_path: &str, origin: &Origin) -> Result<(), Error> {
+		self.write(&reference_path(blob_path, origin), futures::stream::empty::<Result<Bytes, std::io::Error>>(), 0, origin)
+			.await
+	}
+
+	async fn delete_references(&self, blob_path: &str) {
+		let mut refs = self.list(&format!("refs/{blob_path}/"));
+		while let Some(obj) = refs.next().await {
+			let result = match obj {
+				Ok(obj) => self.delete(&obj.key).await,
+				Err(e) => Err(e)
+			};
+			if let Err(error) = result {
+				warn!(blob_path, %error, "Error deleting blob references");
+			}
+		}
+	}
+
+	/// Deletes every blob that no namespace wants anymore.  A blob is kept as long as it's younger
+	/// than the blob invalidation time of at least one namespace that references it; blobs with no
+	/// recorded references, or referenced only by unknown namespaces, fall back to `default`.
+	/// Blobs in `pinned` are always kept.
+	///
+	/// What's deleted is counted under the namespace whose invalidation time it outlived, i.e. the
+	/// referencing namespace with the longest one; blobs with no recorded references are counted
+	/// under an empty namespace.  Also returns what's still there afterwards.
+	pub async fn delete_old_blobs(&self, now: SystemTime, default: Duration, per_namespace: &HashMap<CompactString, Duration>, pinned: &HashSet<String>, dry_run: bool) -> Result<(HashMap<CompactString, Removed>, Removed), Error> {
+		let mut references = HashMap::<String, (Duration, CompactString)>::new();
+		let mut refs = self.list("refs/blobs/");
+		while let Some(obj) = refs.try_next().await? {
+			let Some((blob_path, namespace, _)) = parse_reference_path(&obj.key) else {
+				continue;
+			};
+			let retention = per_namespace.get(namespace).copied().unwrap_or(default);
+			let entry = references.entry(blob_path.into()).or_insert_with(|| (retention, namespace.into()));
+			if (retention > entry.0) {
+				*entry = (retention, namespace.into());
+			}
+		}
+
+		let (mut removed, mut remaining) = (HashMap::<CompactString, Removed>::new(), Removed::default());
+		let mut blobs = self.list("blobs/");
+		while let Some(obj) = blobs.try_next().await? {
+			let (retention, namespace) = references.remove(&obj.key).unwrap_or_else(|| (default, CompactString::default()));
+			if (pinned.contains(&obj.key) || now.checked_sub(retention).map_or(true, |t| obj.modified >= t)) {
+				remaining.add(obj.size);
+				continue;
+			}
+			if (dry_run) {
+				remaining.add(obj.size);
+			} else {
+				if let Err(error) = self.delete(&obj.key).await {
+					error!(path = obj.key, %error, "Error deleting object");
+					remaining.add(obj.size);
+					continue;
+				}
+				info!(path = obj.key, "Aged out");
+				self.delete_references(&obj.key).await;
+			}
+			removed.entry(namespace).or_default().add(obj.size);
+		}
+
+		if (dry_run) {
+			return Ok((removed, remaining));
+		}
+
+		// Whatever is left refers to blobs that have been deleted some other way
+		for blob_path in references.into_keys() {
+			self.delete_references(&blob_path).await;
+		}
+		// Listing skips temp files, so those left behind by blob writes are swept separately
+		self.delete_temp_files("blobs/").await;
+
+		// The local tier of tiered storage is bounded by size rather than purely by age
+		if let Self::Tiered(r) = self {
+			r.evict_local().await;
+		}
+		Ok((removed, remaining))
+	}
+
+	/// Deletes whatever was left behind under `prefix` by writes that never finished, e.g. because
+	/// the process crashed, once it's old enough that the write can't still be in progress
+	pub async fn delete_temp_files(&self, prefix: &str) {
 		match self {
-			Self::S3(r) => r.delete(object).await?,
-			Self::Filesystem(r) => r.delete(object.as_ref()).await?
-		};
-		Ok(())
+			Self::S3(_) => (),
+			Self::Filesystem(r) => r.delete_temp_files(prefix.as_ref()).await,
+			Self::Tiered(r) => r.delete_temp_files(prefix).await
+		}
 	}
 
-	pub async fn delete_old_blobs(&self, older_than: SystemTime) -> Result<usize, Error> {
+	/// Each separate copy of what's stored, by name:  both tiers of tiered storage, or just this
+	/// otherwise.  For maintenance that has to look at every copy of an object, rather than
+	/// whichever one a read would return.
+	pub fn tiers(&self) -> Vec<(&'static str, Repository)> {
 		match self {
-			Self::S3(r) => r.delete_old_objects(older_than, "blobs/").await,
-			Self::Filesystem(r) => r.delete_old_files(older_than, "blobs".as_ref()).await
+			Self::Tiered(r) => vec![("local", Self::Filesystem(r.local().clone())), ("s3", Self::S3(r.remote().clone()))],
+			_ => vec![(self.backend(), self.clone())]
 		}
 	}
 
-	pub async fn delete_old_manifests(&self, ns: &str, older_than: SystemTime) -> Result<usize, Error> {
+	pub fn list(&self, prefix: &str) -> BoxStream<'static, Result<ObjectInfo, Error>> {
+		match self {
+			Self::S3(r) => r.list(prefix),
+			Self::Filesystem(r) => r.list(prefix.as_ref()),
+			Self::Tiered(r) => r.list(prefix)
+		}
+	}
+
+	/// Deletes the least-recently-accessed blobs, other than those in `pinned`, until the total
+	/// size of all blobs is no more than `max_size` bytes
+	pub async fn evict_blobs(&self, max_size: u64, pinned: &HashSet<String>, dry_run: bool) -> Result<Eviction, Error> {
+		match self {
+			Self::S3(r) => r.evict_to_size("blobs/", max_size, pinned, dry_run).await,
+			Self::Filesystem(r) => r.evict_to_size("blobs".as_ref(), max_size, pinned, dry_run).await,
+			Self::Tiered(r) => r.evict_to_size("blobs/", max_size, pinned, dry_run).await
+		}
+	}
+
+	/// Writes out the blob access times that eviction uses, where storage doesn't track them itself
+	pub async fn save_access_times(&self) -> Result<(), Error> {
+		match self {
+			Self::S3(r) => r.save_access_times().await,
+			Self::Filesystem(_) => Ok(()),
+			Self::Tiered(r) => r.save_access_times().await
+		}
+	}
+
+	/// Returns what was aged out, and what's still there afterwards
+	pub async fn delete_old_manifests(&self, ns: &str, older_than: SystemTime, pinned: &HashSet<String>, dry_run: bool) -> Result<(Removed, Removed), Error> {
 		let prefix = format_compact!("manifests/{ns}");
 		let prefix: &str = prefix.as_ref();
 		match self {
-			Self::S3(r) => r.delete_old_objects(older_than, prefix).await,
-			Self::Filesystem(r) => r.delete_old_files(older_than, prefix.as_ref()).await
+			Self::S3(r) => r.delete_old_objects(older_than, prefix, pinned, dry_run).await,
+			Self::Filesystem(r) => r.delete_old_files(older_than, prefix.as_ref(), pinned, dry_run).await,
+			Self::Tiered(r) => r.delete_old_objects(older_than, prefix, pinned, dry_run).await
 		}
 	}
 }
 
+/// The path at which a blob with the given digest is stored
+pub fn blob_path(digest: &Digest) -> String {
+	let (hash_prefix, rest_of_hash) = digest.hex().split_at(2);
+	format!("blobs/{}/{hash_prefix}/{rest_of_hash}", digest.algorithm())
+}
+
+/// The digest of the blob stored at the given path; the inverse of [`blob_path`]
+pub fn blob_digest(path: &str) -> Option<String> {
+	let mut parts = path.strip_prefix("blobs/")?.splitn(3, '/');
+	let (method, hash_prefix, rest_of_hash) = (parts.next()?, parts.next()?, parts.next()?);
+	Some(format!("{method}:{hash_prefix}{rest_of_hash}"))
+}
+
+/// The path at which a manifest is stored
+pub fn manifest_path(namespace: &str, image: &str, reference: &str) -> String {
+	format!("manifests/{namespace}/{image}/{reference}")
+}
+
+/// The namespace of the manifest stored at the given path; the inverse of [`manifest_path`]
+pub fn manifest_namespace(path: &str) -> Option<&str> {
+	path.strip_prefix("manifests/")?.split_once('/').map(|(namespace, _)| namespace)
+}
+
+/// The path of the marker recording that `origin` references the blob stored at `blob_path`
+pub fn reference_path(blob_path: &str, origin: &Origin) -> String {
+	format!("refs/{blob_path}/{}/{}", origin.namespace, origin.repository)
+}
+
+/// Splits a path created by [`reference_path`] into the blob path, namespace, and repository
+pub fn parse_reference_path(path: &str) -> Option<(&str, &str, &str)> {
+	let rest = path.strip_prefix("refs/")?;
+	// Blob paths are always four segments:  blobs/<method>/<hash prefix>/<rest of hash>
+	let split = rest.match_indices('/').nth(3)?.0;
+	let (namespace, repository) = rest[split + 1..].split_once('/')?;
+	Some((&rest[..split], namespace, repository))
+}
+
+#[derive(Clone, Debug)]
+pub struct ObjectInfo {
+	/// Path relative to the root of the storage backend, e.g. `blobs/sha256/ab/cdef...`
+	pub key: String,
+	pub size: u64,
+	pub modified: SystemTime
+}
+
+#[derive(Clone, Copy, Debug, Default, Serialize)]
+pub struct Eviction {
+	/// Number of objects deleted, or that would have been deleted in a dry run
+	pub count: usize,
+	/// Total size of objects deleted, or that would have been deleted in a dry run
+	pub bytes: u64,
+	/// Total size of objects remaining
+	pub remaining: u64
+}
+
+/// Objects that were aged out, or that would have been in a dry run; also used to count what's
+/// left
+#[derive(Clone, Copy, Debug, Default, Serialize)]
+pub struct Removed {
+	pub count: usize,
+	pub bytes: u64
+}
+
+impl Removed {
+	pub fn add(&mut self, size: u64) {
+		self.count += 1;
+		self.bytes += size;
+	}
+
+	pub fn add_all(&mut self, other: Removed) {
+		self.count += other.count;
+		self.bytes += other.bytes;
+	}
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct Manifest {
 	pub manifest: Bytes,
@@ -124,3 +449,37 @@ impl Manifest {
 		Self { manifest, media_type, digest }
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn blob_path_round_trip() {
+		let digest = "sha256:6864e61916f58174557076c34e7122753331cf28077edb0f23e1fb5419dd6acd";
+		let path = blob_path(&digest.parse().unwrap());
+		assert_eq!(path, "blobs/sha256/68/64e61916f58174557076c34e7122753331cf28077edb0f23e1fb5419dd6acd");
+		assert_eq!(blob_digest(&path).as_deref(), Some(digest));
+	}
+
+	#[test]
+	fn reference_path_round_trip() {
+		let blob_path = "blobs/sha256/68/64e61916f58174557076c34e7122753331cf28077edb0f23e1fb5419dd6acd";
+		let path = reference_path(blob_path, &Origin::new("docker.io", "grafana/grafana"));
+		assert_eq!(parse_reference_path(&path), Some((blob_path, "docker.io", "grafana/grafana")));
+		assert_eq!(parse_reference_path("refs/blobs/sha256/68/64e6"), None);
+	}
+
+	#[test]
+	fn manifest_namespace_round_trip() {
+		let path = manifest_path("docker.io", "library/busybox", "latest");
+		assert_eq!(manifest_namespace(&path), Some("docker.io"));
+		assert_eq!(manifest_namespace("blobs/sha256/68/64e6"), None);
+	}
+
+	#[test]
+	fn blob_digest_invalid() {
+		assert_eq!(blob_digest("manifests/docker.io/library/busybox/latest"), None);
+		assert_eq!(blob_digest("blobs/sha256/68"), None);
+	}
+}
@@ -1,4 +1,6 @@
+use actix_web::http::StatusCode;
 use arcerror::ArcError;
+use rusoto_core::request::BufferedHttpResponse;
 use rusoto_core::RusotoError;
 
 use crate::api::stream::DigestMismatchError;
@@ -11,10 +13,16 @@ pub enum Error {
 	RusotoList(ArcError<RusotoError<rusoto_s3::ListObjectsV2Error>>),
 	#[error("Failed to get object from S3: {0:?}")]
 	RusotoGet(ArcError<RusotoError<rusoto_s3::GetObjectError>>),
+	#[error("Failed to get object metadata from S3: {0:?}")]
+	RusotoHead(ArcError<RusotoError<rusoto_s3::HeadObjectError>>),
+	#[error("Failed to load S3 credentials: {0}")]
+	Credentials(#[from] rusoto_credential::CredentialsError),
 	#[error("Failed to put object into S3: {0:?}")]
 	RusotoPut(ArcError<RusotoError<rusoto_s3::PutObjectError>>),
 	#[error("Failed to delete object from S3: {0:?}")]
 	RusotoDelete(ArcError<RusotoError<rusoto_s3::DeleteObjectError>>),
+	#[error("Failed to delete objects from S3: {0:?}")]
+	RusotoDeleteMany(ArcError<RusotoError<rusoto_s3::DeleteObjectsError>>),
 	#[error("Failed to parse datetime: {0}")]
 	ParseTime(#[from] time::error::Parse),
 	#[error("Object too old: {0}")]
@@ -22,7 +30,26 @@ pub enum Error {
 	#[error("Error reading from upstream: {0}")]
 	Upstream(ArcError<dkregistry::errors::Error>),
 	#[error("{0}")]
-	DataCorrupt(#[from] DigestMismatchError)
+	DataCorrupt(#[from] DigestMismatchError),
+	#[error("Cached manifest {0} can't be parsed: {1}")]
+	InvalidManifest(String, ArcError<serde_json::Error>),
+	#[error("Invalid storage configuration: {0}")]
+	Config(String)
+}
+
+impl Error {
+	/// Whether this error means that the object doesn't exist
+	pub fn is_not_found(&self) -> bool {
+		match self {
+			Self::Io(e) => e.kind() == std::io::ErrorKind::NotFound,
+			Self::RusotoGet(e) => matches!(e.as_ref(), RusotoError::Service(rusoto_s3::GetObjectError::NoSuchKey(_))),
+			Self::RusotoHead(e) => matches!(
+				e.as_ref(),
+				RusotoError::Service(rusoto_s3::HeadObjectError::NoSuchKey(_)) | RusotoError::Unknown(BufferedHttpResponse { status: StatusCode::NOT_FOUND, .. })
+			),
+			_ => false
+		}
+	}
 }
 
 impl From<std::io::Error> for Error {
@@ -46,6 +73,13 @@ impl From<RusotoError<rusoto_s3::GetObjectError>> for Error {
 	}
 }
 
+impl From<RusotoError<rusoto_s3::HeadObjectError>> for Error {
+	#[inline]
+	fn from(inner: RusotoError<rusoto_s3::HeadObjectError>) -> Self {
+		Self::RusotoHead(ArcError::from(inner))
+	}
+}
+
 impl From<RusotoError<rusoto_s3::PutObjectError>> for Error {
 	#[inline]
 	fn from(inner: RusotoError<rusoto_s3::PutObjectError>) -> Self {
@@ -60,6 +94,13 @@ impl From<RusotoError<rusoto_s3::DeleteObjectError>> for Error {
 	}
 }
 
+impl From<RusotoError<rusoto_s3::DeleteObjectsError>> for Error {
+	#[inline]
+	fn from(inner: RusotoError<rusoto_s3::DeleteObjectsError>) -> Self {
+		Self::RusotoDeleteMany(ArcError::from(inner))
+	}
+}
+
 impl From<dkregistry::errors::Error> for Error {
 	#[inline]
 	fn from(inner: dkregistry::errors::Error) -> Self {
@@ -1,5 +1,10 @@
 use core::time::Duration;
+use std::collections::HashSet;
+use std::fs::FileTimes;
 use std::path::Path;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
 use std::time::SystemTime;
 
 use actix_web::web::Bytes;
@@ -9,11 +14,14 @@ use camino::Utf8Component;
 use camino::Utf8Path;
 use camino::Utf8PathBuf;
 use clap::Parser;
+use futures::stream::BoxStream;
 use futures::stream::StreamExt;
 use futures::stream::TryStream;
 use futures::stream::TryStreamExt;
 use tokio::fs::create_dir_all;
+use tokio::fs::remove_dir_all;
 use tokio::fs::remove_file;
+use tokio::fs::rename;
 use tokio::fs::symlink_metadata;
 use tokio::fs::File;
 use tokio::fs::OpenOptions;
@@ -23,9 +31,54 @@ use tokio::io::BufReader;
 use tokio::io::BufWriter;
 use tracing::error;
 use tracing::info;
+use tracing::warn;
 
 use super::ReadStream;
 
+/// Objects are written to a file with this suffix and renamed into place once complete, so that
+/// a partially-written object is never visible at its real path
+const TEMP_SUFFIX: &str = ".tmp";
+
+/// A temp file that hasn't been modified in this long belongs to a write that was abandoned
+const ABANDONED_TEMP_FILE_AGE: Duration = Duration::from_secs(3600);
+
+/// A unique path in the same directory as `path` to write it to before renaming it into place
+fn temp_path(path: &Utf8Path) -> Utf8PathBuf {
+	static COUNTER: AtomicU64 = AtomicU64::new(0);
+	let name = format!(".{}.{}.{}{TEMP_SUFFIX}", path.file_name().unwrap_or_default(), std::process::id(), COUNTER.fetch_add(1, Ordering::Relaxed));
+	path.with_file_name(name)
+}
+
+fn is_temp_file(path: &Path) -> bool {
+	path.file_name().and_then(|n| n.to_str()).map_or(false, |n| n.starts_with('.') && n.ends_with(TEMP_SUFFIX))
+}
+
+/// Whether a temp file last modified at `modified` belongs to a write that was abandoned.  The
+/// pid in its name can't tell us, since the cache may be shared with other replicas, whose pids
+/// mean nothing here, and in a container every run has the same pid anyway.
+fn is_abandoned(modified: SystemTime) -> bool {
+	SystemTime::now().duration_since(modified).unwrap_or_default() > ABANDONED_TEMP_FILE_AGE
+}
+
+fn read_file(file: File, length: u64, modified: SystemTime) -> ReadStream {
+	let mut file = BufReader::with_capacity(16384, file);
+	ReadStream::new(
+		length,
+		modified,
+		Box::pin(try_stream! {
+			loop {
+				let buf = file.fill_buf().await?;
+				if(buf.is_empty()) {
+					break;
+				}
+				let len = buf.len();
+				yield Bytes::copy_from_slice(buf);
+				file.consume(len);
+			}
+		})
+	)
+}
+
 #[derive(Clone, Debug, Parser)]
 pub struct Config {
 	#[clap(env = "FILESYSTEM_ROOT", long)]
@@ -49,30 +102,35 @@ impl Repository {
 		self.root.join(path)
 	}
 
+	/// The inverse of [`full_path`](Self::full_path)
+	fn key<'a>(&self, path: &'a Path) -> Option<&'a str> {
+		path.strip_prefix(&self.root).ok().and_then(|p| p.to_str())
+	}
+
 	pub async fn read(&self, object: &Utf8Path, invalidation: Duration) -> Result<ReadStream, super::Error> {
 		let path = self.full_path(object);
-		let (age, length) = {
+		let (age, modified, length) = {
 			let metadata = symlink_metadata(&path).await?;
-			(SystemTime::now().duration_since(metadata.modified()?).unwrap_or_default(), metadata.len())
+			let modified = metadata.modified()?;
+			(SystemTime::now().duration_since(modified).unwrap_or_default(), modified, metadata.len())
 		};
 		if (age > invalidation) {
 			return Err(super::Error::ObjectTooOld(age.into()));
 		}
-		let mut file = BufReader::with_capacity(16384, File::open(path).await?);
-		Ok(ReadStream::new(
-			length,
-			Box::pin(try_stream! {
-				loop {
-					let buf = file.fill_buf().await?;
-					if(buf.is_empty()) {
-						break;
-					}
-					let len = buf.len();
-					yield Bytes::copy_from_slice(buf);
-					file.consume(len);
-				}
-			})
-		))
+		let file = File::open(&path).await?.into_std().await;
+		// Explicitly record the access, regardless of mount options, for least-recently-used eviction
+		if let Err(error) = file.set_times(FileTimes::new().set_accessed(SystemTime::now())) {
+			warn!(%path, %error, "Failed to update atime");
+		}
+		Ok(read_file(File::from_std(file), length, modified))
+	}
+
+	/// Reads an object regardless of its age, without recording an access for eviction
+	pub async fn read_unrecorded(&self, object: &Utf8Path) -> Result<ReadStream, super::Error> {
+		let path = self.full_path(object);
+		let metadata = symlink_metadata(&path).await?;
+		let file = File::open(&path).await?;
+		Ok(read_file(file, metadata.len(), metadata.modified()?))
 	}
 
 	pub async fn write<S, E>(&self, object: &Utf8Path, reader: S) -> Result<(), super::Error>
@@ -100,24 +158,182 @@ impl Repository {
 		if let Some(parent) = path.parent() {
 			create_dir_all(parent).await?;
 		}
-		let file = OpenOptions::default().create(true).read(false).write(true).truncate(true).open(&path).await?;
+		let temp = temp_path(&path);
+		let file = OpenOptions::default().create_new(true).read(false).write(true).open(&temp).await?;
 		let mut file = BufWriter::with_capacity(16384, file);
 
-		match _write(&mut file, reader).await {
-			Ok(_) => Ok(file.flush().await?),
-			Err(e) => {
-				self.delete(object.as_ref()).await?;
-				Err(e)
+		let result = match _write(&mut file, reader).await {
+			Ok(_) => async {
+				file.flush().await?;
+				file.get_ref().sync_all().await?;
+				rename(&temp, &path).await?;
+				// Otherwise the rename itself could be lost in a crash
+				match path.parent() {
+					Some(parent) => File::open(parent).await?.sync_all().await,
+					None => Ok(())
+				}
+			}
+			.await
+			.map_err(super::Error::from),
+			Err(e) => Err(e)
+		};
+		if (result.is_err()) {
+			if let Err(error) = remove_file(&temp).await {
+				warn!(path = %temp, %error, "Failed to delete temp file");
 			}
 		}
+		result
+	}
+
+	/// Deletes temp files under `prefix` left behind by writes that never finished, e.g. because
+	/// the process crashed.  Those that have been modified recently may belong to writes that are
+	/// still in progress, in this process or another sharing the cache, so they're left alone.
+	pub async fn delete_temp_files(&self, prefix: &Utf8Path) {
+		let mut count = 0;
+		let root = self.root.join(prefix);
+		let mut entries = WalkDir::new(&root);
+		while let Some(entry) = entries.next().await {
+			let entry = match entry {
+				Ok(v) => v,
+				Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+				Err(error) => {
+					error!(path = %root, %error, "Error walking directory");
+					continue;
+				}
+			};
+			let path = entry.path();
+			if (!is_temp_file(&path)) {
+				continue;
+			}
+			match entry.metadata().await.and_then(|m| m.modified()) {
+				Ok(modified) if is_abandoned(modified) => (),
+				Ok(_) => continue,
+				Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+				Err(error) => {
+					error!(path = %path.display(), %error, "Error reading mtime");
+					continue;
+				}
+			};
+			match self.delete(&path).await {
+				Ok(_) => count += 1,
+				Err(error) => error!(path = %path.display(), %error, "Error deleting temp file")
+			};
+		}
+		if (count > 0) {
+			info!(count, "Deleted abandoned temp files");
+		}
 	}
 
 	pub async fn delete(&self, path: &Path) -> Result<(), std::io::Error> {
 		remove_file(path).await
 	}
 
-	pub async fn delete_old_files(&self, older_than: SystemTime, prefix: &Utf8Path) -> Result<usize, super::Error> {
-		let mut count = 0;
+	/// Deletes the directory `prefix` and everything in it, and returns how many objects were in it
+	pub async fn delete_prefix(&self, prefix: &Utf8Path) -> Result<usize, super::Error> {
+		let count = self.list(prefix).try_fold(0, |count, _| futures::future::ready(Ok::<_, super::Error>(count + 1))).await?;
+		match remove_dir_all(self.full_path(prefix)).await {
+			Ok(_) => Ok(count),
+			Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(0),
+			Err(e) => Err(e.into())
+		}
+	}
+
+	pub fn list(&self, prefix: &Utf8Path) -> BoxStream<'static, Result<super::ObjectInfo, super::Error>> {
+		let root = self.root.clone();
+		let mut entries = WalkDir::new(root.join(prefix));
+		Box::pin(try_stream! {
+			while let Some(entry) = entries.next().await {
+				let entry = match entry {
+					// Nothing has been cached under this prefix yet
+					Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+					entry => entry?
+				};
+				let metadata = entry.metadata().await?;
+				if (!metadata.is_file()) {
+					continue;
+				}
+				let path = entry.path();
+				if (is_temp_file(&path)) {
+					continue;
+				}
+				let Some(key) = path.strip_prefix(&root).ok().and_then(|p| p.to_str()) else {
+					continue;
+				};
+				yield super::ObjectInfo { key: key.into(), size: metadata.len(), modified: metadata.modified()? };
+			}
+		})
+	}
+
+	pub async fn delete_object(&self, object: &Utf8Path) -> Result<(), std::io::Error> {
+		self.delete(self.full_path(object).as_ref()).await
+	}
+
+	pub async fn set_modified(&self, object: &Utf8Path, modified: SystemTime) -> Result<(), std::io::Error> {
+		let file = File::options().write(true).open(self.full_path(object)).await?.into_std().await;
+		file.set_times(FileTimes::new().set_modified(modified))
+	}
+
+	/// Deletes the least-recently-accessed files under `prefix`, other than those in `keep`, until
+	/// their total size is no more than `max_size` bytes.
+	pub async fn evict_to_size(&self, prefix: &Utf8Path, max_size: u64, keep: &HashSet<String>, dry_run: bool) -> Result<super::Eviction, super::Error> {
+		let mut files = Vec::<(SystemTime, u64, PathBuf)>::new();
+		let mut total = 0;
+		let mut entries = WalkDir::new(self.root.join(prefix));
+		while let Some(entry) = entries.next().await {
+			let entry = match entry {
+				Ok(v) => v,
+				Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+				Err(error) => {
+					error!(path = %prefix, %error, "Error walking directory");
+					continue;
+				}
+			};
+			// Still being written, or about to be cleaned up; either way, not ours to evict
+			if (is_temp_file(&entry.path())) {
+				continue;
+			}
+			let metadata = match entry.metadata().await {
+				Ok(v) if v.is_file() => v,
+				Ok(_) => continue,
+				Err(error) => {
+					error!(path = %entry.path().display(), %error, "Error reading metadata");
+					continue;
+				}
+			};
+			let accessed = metadata.accessed().or_else(|_| metadata.modified()).unwrap_or(SystemTime::UNIX_EPOCH);
+			total += metadata.len();
+			let path = entry.path();
+			if (self.key(&path).map_or(false, |key| keep.contains(key))) {
+				continue;
+			}
+			files.push((accessed, metadata.len(), path));
+		}
+		let mut eviction = super::Eviction::default();
+		files.sort_unstable_by_key(|(accessed, _, _)| *accessed);
+		for (_, size, path) in files {
+			if (total <= max_size) {
+				break;
+			}
+			if (!dry_run) {
+				match self.delete(&path).await {
+					Ok(_) => info!(path = %path.display(), "Evicted"),
+					Err(error) => {
+						error!(path = %path.display(), %error, "Error deleting object");
+						continue;
+					}
+				}
+			}
+			total -= size;
+			eviction.count += 1;
+			eviction.bytes += size;
+		}
+		eviction.remaining = total;
+		Ok(eviction)
+	}
+
+	/// Returns what was aged out, and what's still there afterwards
+	pub async fn delete_old_files(&self, older_than: SystemTime, prefix: &Utf8Path, keep: &HashSet<String>, dry_run: bool) -> Result<(super::Removed, super::Removed), super::Error> {
+		let (mut removed, mut remaining) = (super::Removed::default(), super::Removed::default());
 		let root = self.root.join(prefix);
 		let mut entries = WalkDir::new(root);
 		let mut first_iteration = true;
@@ -150,17 +366,48 @@ impl Repository {
 					continue;
 				}
 			};
-			if (modified < older_than) {
+			if (is_temp_file(&path)) {
+				if (!dry_run && is_abandoned(modified)) {
+					match self.delete(&path).await {
+						Ok(_) => info!(path = %path.display(), "Deleted abandoned temp file"),
+						Err(error) => error!(path = %path.display(), %error, "Error deleting temp file")
+					};
+				}
+				continue;
+			}
+			if (modified >= older_than || self.key(&path).map_or(false, |key| keep.contains(key))) {
+				remaining.add(metadata.len());
+				continue;
+			}
+			if (dry_run) {
+				remaining.add(metadata.len());
+			} else {
 				match self.delete(&path).await {
 					Ok(_) => info!(path = %path.display(), "Aged out"),
 					Err(error) => {
 						error!(path = %path.display(), %error, "Error deleting object");
+						remaining.add(metadata.len());
 						continue;
 					}
 				}
-				count += 1;
 			}
+			removed.add(metadata.len());
 		}
-		Ok(count)
+		Ok((removed, remaining))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn temp_paths() {
+		let path = Utf8Path::new("/cache/blobs/sha256/ab/cdef");
+		let (a, b) = (temp_path(path), temp_path(path));
+		assert_ne!(a, b);
+		assert_eq!(a.parent(), path.parent());
+		assert!(is_temp_file(a.as_std_path()));
+		assert!(!is_temp_file(path.as_std_path()));
 	}
 }
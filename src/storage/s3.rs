@@ -1,75 +1,263 @@
 use core::pin::Pin;
 use core::time::Duration;
+use std::collections::HashMap;
+use std::collections::HashSet;
 use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::Mutex;
 use std::time::SystemTime;
 use std::vec::IntoIter;
 
 use actix_web::web::Bytes;
+use async_stream::try_stream;
+use async_trait::async_trait;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use bytes::BytesMut;
 use clap::Parser;
+use clap::ValueEnum;
 use compact_str::CompactString;
 use futures::future::BoxFuture;
 use futures::future::FutureExt;
+use futures::stream::BoxStream;
 use futures::stream::Stream;
 use futures::stream::StreamExt;
 use futures::stream::TryStream;
 use futures::stream::TryStreamExt;
 use futures::task::Context;
 use futures::task::Poll;
+use md5::Digest;
+use md5::Md5;
 use rusoto_core::request::HttpClient;
 use rusoto_core::ByteStream;
 use rusoto_core::Region;
 use rusoto_core::RusotoError;
+use rusoto_credential::AutoRefreshingProvider;
+use rusoto_credential::AwsCredentials;
+use rusoto_credential::CredentialsError;
+use rusoto_credential::DefaultCredentialsProvider;
+use rusoto_credential::ProvideAwsCredentials;
 use rusoto_credential::StaticProvider;
+use rusoto_s3::util::PreSignedRequest;
+use rusoto_s3::util::PreSignedRequestOption;
+use rusoto_s3::Delete;
 use rusoto_s3::DeleteObjectError;
 use rusoto_s3::DeleteObjectRequest;
+use rusoto_s3::DeleteObjectsRequest;
 use rusoto_s3::GetObjectError;
 use rusoto_s3::GetObjectOutput;
 use rusoto_s3::GetObjectRequest;
+use rusoto_s3::HeadObjectError;
+use rusoto_s3::HeadObjectOutput;
+use rusoto_s3::HeadObjectRequest;
 use rusoto_s3::ListObjectsV2Error;
 use rusoto_s3::ListObjectsV2Output;
 use rusoto_s3::ListObjectsV2Request;
+use rusoto_s3::ObjectIdentifier;
 use rusoto_s3::PutObjectRequest;
 use rusoto_s3::S3Client;
 use rusoto_s3::S3;
+use rusoto_sts::StsAssumeRoleSessionCredentialsProvider;
+use rusoto_sts::StsClient;
+use rusoto_sts::WebIdentityProvider;
 use time::format_description::well_known::Rfc2822;
 use time::format_description::well_known::Rfc3339;
 use time::OffsetDateTime;
 use tracing::info;
+use tracing::warn;
 
+use super::Origin;
 use super::ReadStream;
+use crate::util::SecretString;
 
 #[derive(Clone, Debug, Parser)]
 pub struct Config {
 	#[clap(env = "S3_HOST", long)]
 	host: Option<String>,
-	#[clap(env = "S3_ACCESS_KEY", long)]
-	access_key: CompactString,
-	#[clap(env = "S3_SECRET_KEY", long)]
-	secret_key: String,
+	/// If neither this nor --secret-key are passed, credentials will be loaded from the standard
+	/// AWS provider chain:  web identity tokens (e.g. IRSA), environment variables, profiles in
+	/// ~/.aws/credentials, ECS task roles, and EC2 instance profiles, in that order.
+	#[clap(env = "S3_ACCESS_KEY", long, requires = "secret_key")]
+	access_key: Option<CompactString>,
+	#[clap(env = "S3_SECRET_KEY", long, requires = "access_key")]
+	secret_key: Option<String>,
+	/// If passed, the credentials found as described for --access-key will be used to assume
+	/// this role, and the resulting temporary credentials will be used to access S3
+	#[clap(env = "S3_ROLE_ARN", long)]
+	role_arn: Option<String>,
+	#[clap(env = "S3_ROLE_SESSION_NAME", long, default_value = "oci-registry")]
+	role_session_name: String,
 	#[clap(env = "S3_REGION", long, default_value = "us-east-1")]
 	region: CompactString,
 	#[clap(env = "S3_BUCKET", long)]
-	bucket: CompactString
+	bucket: CompactString,
+	/// Server-side encryption to request for every object written
+	#[clap(env = "S3_SERVER_SIDE_ENCRYPTION", long, value_enum, conflicts_with = "sse_customer_key")]
+	server_side_encryption: Option<ServerSideEncryption>,
+	/// The KMS key to encrypt objects with, if --server-side-encryption is aws:kms; if not passed,
+	/// the bucket's default KMS key will be used
+	#[clap(env = "S3_SSE_KMS_KEY_ID", long, requires = "server_side_encryption")]
+	sse_kms_key_id: Option<String>,
+	/// A base64-encoded 256-bit key with which to encrypt objects with SSE-C.  The same key is
+	/// required to read them back, so changing it effectively empties the cache.
+	#[clap(env = "S3_SSE_CUSTOMER_KEY", long)]
+	sse_customer_key: Option<SecretString>,
+	/// Storage class for objects under blobs/, e.g. INTELLIGENT_TIERING; if not passed, the
+	/// bucket's default will be used
+	#[clap(env = "S3_BLOB_STORAGE_CLASS", long)]
+	blob_storage_class: Option<String>,
+	/// Storage class for objects under manifests/, e.g. STANDARD; if not passed, the bucket's
+	/// default will be used
+	#[clap(env = "S3_MANIFEST_STORAGE_CLASS", long)]
+	manifest_storage_class: Option<String>,
+	/// If enabled, objects will be tagged with the namespace and repository they were pulled
+	/// from, for use in lifecycle rules and cost reports.  Blobs shared between repositories will
+	/// only be tagged with the first one that caused them to be cached.
+	#[clap(env = "S3_TAG_OBJECTS", long, default_value_t = false)]
+	tag_objects: bool
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum ServerSideEncryption {
+	#[value(name = "AES256")]
+	Aes256,
+	#[value(name = "aws:kms")]
+	AwsKms
+}
+
+impl ServerSideEncryption {
+	fn as_str(&self) -> &'static str {
+		match self {
+			Self::Aes256 => "AES256",
+			Self::AwsKms => "aws:kms"
+		}
+	}
 }
 
 impl Config {
-	pub fn repository(&self) -> Repository {
+	pub fn repository(&self) -> Result<Repository, super::Error> {
 		let region = match self.host.clone() {
 			Some(s) => Region::Custom { name: self.region.to_string(), endpoint: s },
-			None => Region::from_str(&self.region).unwrap()
+			None => Region::from_str(&self.region).map_err(|e| super::Error::Config(format!("--region: {e}")))?
+		};
+		let credentials = match (self.access_key.as_ref(), self.secret_key.as_ref()) {
+			(Some(access_key), Some(secret_key)) => self.credentials(StaticProvider::new(access_key.to_string(), secret_key.clone(), None, None))?,
+			// IRSA is signaled by these environment variables; rusoto's default chain doesn't check for web identity tokens itself
+			_ if std::env::var_os("AWS_WEB_IDENTITY_TOKEN_FILE").is_some() && std::env::var_os("AWS_ROLE_ARN").is_some() => self.credentials(AutoRefreshingProvider::new(WebIdentityProvider::from_k8s_env())?)?,
+			_ => self.credentials(DefaultCredentialsProvider::new()?)?
+		};
+		let options = ObjectOptions {
+			server_side_encryption: self.server_side_encryption,
+			sse_kms_key_id: self.sse_kms_key_id.clone(),
+			sse_customer_key: self.sse_customer_key.clone().map(CustomerKey::new).transpose()?,
+			blob_storage_class: self.blob_storage_class.clone(),
+			manifest_storage_class: self.manifest_storage_class.clone(),
+			tag_objects: self.tag_objects
+		};
+		Ok(Repository {
+			inner: S3Client::new_with(http_client()?, credentials.clone(), region.clone()),
+			credentials,
+			region,
+			bucket: self.bucket.clone(),
+			options: Arc::new(options),
+			accessed: Arc::default(),
+			access_index: access_index_path().into()
+		})
+	}
+
+	fn credentials<P>(&self, base: P) -> Result<Credentials, super::Error>
+	where
+		P: ProvideAwsCredentials + Send + Sync + 'static
+	{
+		Ok(match self.role_arn.as_ref() {
+			None => Credentials(Arc::new(base)),
+			Some(role_arn) => {
+				// A custom S3 endpoint doesn't imply anything about where STS lives, so only use the region name
+				let sts_region = Region::from_str(&self.region).unwrap_or_default();
+				let sts = StsClient::new_with(http_client()?, base, sts_region);
+				let provider = StsAssumeRoleSessionCredentialsProvider::new(sts, role_arn.clone(), self.role_session_name.clone(), None, None, None, None);
+				Credentials(Arc::new(AutoRefreshingProvider::new(provider)?))
+			}
+		})
+	}
+}
+
+fn http_client() -> Result<HttpClient, super::Error> {
+	HttpClient::new().map_err(|e| super::Error::Config(format!("Failed to set up TLS: {e}")))
+}
+
+/// Type-erased credentials provider, so that the same credentials used by the S3 client can also
+/// be used to pre-sign URLs
+#[derive(Clone)]
+struct Credentials(Arc<dyn ProvideAwsCredentials + Send + Sync>);
+
+#[async_trait]
+impl ProvideAwsCredentials for Credentials {
+	async fn credentials(&self) -> Result<AwsCredentials, CredentialsError> {
+		self.0.credentials().await
+	}
+}
+
+#[derive(Clone)]
+struct CustomerKey {
+	key: SecretString,
+	key_md5: String
+}
+
+impl CustomerKey {
+	fn new(key: SecretString) -> Result<Self, super::Error> {
+		let raw = BASE64.decode(key.as_ref()).ok().filter(|raw| raw.len() == 32);
+		let Some(raw) = raw else {
+			return Err(super::Error::Config("--sse-customer-key must be a base64-encoded 256-bit key".to_owned()));
 		};
-		let creds = StaticProvider::new(self.access_key.to_string(), self.secret_key.clone(), None, None);
-		let http = HttpClient::new().unwrap();
-		Repository {
-			inner: S3Client::new_with(http, creds, region),
-			bucket: self.bucket.clone()
+		let key_md5 = BASE64.encode(Md5::digest(&raw));
+		Ok(Self { key, key_md5 })
+	}
+}
+
+struct ObjectOptions {
+	server_side_encryption: Option<ServerSideEncryption>,
+	sse_kms_key_id: Option<String>,
+	sse_customer_key: Option<CustomerKey>,
+	blob_storage_class: Option<String>,
+	manifest_storage_class: Option<String>,
+	tag_objects: bool
+}
+
+impl ObjectOptions {
+	fn storage_class(&self, object: &str) -> Option<String> {
+		match object.starts_with("blobs/") {
+			true => self.blob_storage_class.clone(),
+			false => self.manifest_storage_class.clone()
 		}
 	}
+
+	fn tagging(&self, origin: &Origin) -> Option<String> {
+		if (!self.tag_objects) {
+			return None;
+		}
+		let namespace: &str = origin.namespace.as_ref();
+		let repository: &str = origin.repository.as_ref();
+		serde_urlencoded::to_string([("namespace", namespace), ("repository", repository)]).ok()
+	}
+
+	fn sse_customer_algorithm(&self) -> Option<String> {
+		self.sse_customer_key.as_ref().map(|_| "AES256".into())
+	}
+
+	fn sse_customer_key(&self) -> Option<String> {
+		self.sse_customer_key.as_ref().map(|k| k.key.as_ref().into())
+	}
+
+	fn sse_customer_key_md5(&self) -> Option<String> {
+		self.sse_customer_key.as_ref().map(|k| k.key_md5.clone())
+	}
 }
 
 struct ListObjectsStream {
 	client: S3Client,
 	bucket: CompactString,
+	prefix: Option<String>,
 	current_continuation_token: Option<String>,
 	current_contents: IntoIter<rusoto_s3::Object>,
 	current_future: Option<BoxFuture<'static, Result<ListObjectsV2Output, RusotoError<ListObjectsV2Error>>>>
@@ -106,9 +294,11 @@ impl Stream for ListObjectsStream {
 		self.current_future = {
 			let client = Box::pin(self.client.clone());
 			let bucket = self.bucket.to_string();
+			let prefix = self.prefix.clone();
 			Some(Box::pin(async move {
 				client.list_objects_v2(ListObjectsV2Request{
 					bucket,
+					prefix,
 					continuation_token: Some(token),
 					..Default::default()
 				}).await
@@ -129,7 +319,33 @@ impl Stream for ListObjectsStream {
 #[derive(Clone)]
 pub struct Repository {
 	inner: S3Client,
-	bucket: CompactString
+	credentials: Credentials,
+	region: Region,
+	bucket: CompactString,
+	options: Arc<ObjectOptions>,
+	/// When this replica last read each blob, as seconds since the UNIX epoch
+	accessed: Arc<Mutex<HashMap<String, u64>>>,
+	/// Where this replica writes `accessed`
+	access_index: Arc<str>
+}
+
+/// S3 doesn't track access times, so we keep our own, as JSON maps from key to seconds since the
+/// UNIX epoch.  Each replica sharing the bucket writes its own map under this prefix, so that none
+/// of them overwrite another's, and eviction merges them all.  What a replica has recorded since
+/// it last wrote its map isn't seen by eviction on other replicas.
+const ACCESS_INDEX_PREFIX: &str = "_oci-registry/access-times/";
+
+/// A replica's map that hasn't been written for this long is assumed to belong to a replica that
+/// has gone away; the next eviction takes over its entries and deletes it
+const ABANDONED_ACCESS_INDEX_AGE: Duration = Duration::from_secs(86400);
+
+fn access_index_path() -> String {
+	let hostname = std::env::var("HOSTNAME").unwrap_or_default();
+	format!("{ACCESS_INDEX_PREFIX}{hostname}-{}-{}.json", std::process::id(), unix_time(SystemTime::now()))
+}
+
+fn unix_time(time: SystemTime) -> u64 {
+	time.duration_since(SystemTime::UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default()
 }
 
 impl Repository {
@@ -143,6 +359,7 @@ impl Repository {
 		Ok(ListObjectsStream {
 			client: self.inner.clone(),
 			bucket: self.bucket.clone(),
+			prefix: Some(prefix.into()),
 			current_continuation_token: result.continuation_token,
 			current_contents: result.contents.unwrap_or_default().into_iter(),
 			current_future: None
@@ -153,34 +370,99 @@ impl Repository {
 		let req = GetObjectRequest {
 			bucket: self.bucket.to_string(),
 			key: object.into(),
+			sse_customer_algorithm: self.options.sse_customer_algorithm(),
+			sse_customer_key: self.options.sse_customer_key(),
+			sse_customer_key_md5: self.options.sse_customer_key_md5(),
 			..Default::default()
 		};
 		self.inner.get_object(req).await
 	}
 
-	pub async fn read(&self, object: &str, invalidation: Duration) -> Result<ReadStream, super::Error> {
-		let obj = self.get_object(object).await?;
+	async fn head_object(&self, object: &str) -> Result<HeadObjectOutput, RusotoError<HeadObjectError>> {
+		let req = HeadObjectRequest {
+			bucket: self.bucket.to_string(),
+			key: object.into(),
+			sse_customer_algorithm: self.options.sse_customer_algorithm(),
+			sse_customer_key: self.options.sse_customer_key(),
+			sse_customer_key_md5: self.options.sse_customer_key_md5(),
+			..Default::default()
+		};
+		self.inner.head_object(req).await
+	}
+
+	/// Returns a pre-signed GetObject URL for the object, valid for `expires_in`, if the object
+	/// exists and is younger than `invalidation`.  Objects encrypted with SSE-C can't be fetched
+	/// without the key, so `None` is returned for them.
+	pub async fn presigned_url(&self, object: &str, invalidation: Duration, expires_in: Duration) -> Result<Option<String>, super::Error> {
+		if (self.options.sse_customer_key.is_some()) {
+			return Ok(None);
+		}
+		let obj = self.head_object(object).await?;
 		let time = obj.last_modified.map(|s| OffsetDateTime::parse(&s, &Rfc2822)).transpose()?.unwrap_or(OffsetDateTime::UNIX_EPOCH);
 		let age = Duration::try_from(SystemTime::now() - time).unwrap_or_default();
 		if (age > invalidation) {
 			return Err(super::Error::ObjectTooOld(age.into()));
 		}
 
-		Ok(ReadStream::new(obj.content_length.unwrap().try_into().unwrap_or_default(), Box::pin(obj.body.unwrap())))
+		self.record_access(object);
+		let credentials = self.credentials.credentials().await?;
+		let req = GetObjectRequest {
+			bucket: self.bucket.to_string(),
+			key: object.into(),
+			..Default::default()
+		};
+		Ok(Some(req.get_presigned_url(&self.region, &credentials, &PreSignedRequestOption { expires_in })))
+	}
+
+	pub async fn read(&self, object: &str, invalidation: Duration) -> Result<ReadStream, super::Error> {
+		let stream = self.read_unrecorded(object).await?;
+		let age = SystemTime::now().duration_since(stream.modified()).unwrap_or_default();
+		if (age > invalidation) {
+			return Err(super::Error::ObjectTooOld(age.into()));
+		}
+
+		self.record_access(object);
+		Ok(stream)
+	}
+
+	/// Reads an object regardless of its age, without recording an access for eviction
+	pub async fn read_unrecorded(&self, object: &str) -> Result<ReadStream, super::Error> {
+		let obj = self.get_object(object).await?;
+		let time = obj.last_modified.map(|s| OffsetDateTime::parse(&s, &Rfc2822)).transpose()?.unwrap_or(OffsetDateTime::UNIX_EPOCH);
+		Ok(ReadStream::new(obj.content_length.unwrap().try_into().unwrap_or_default(), time.into(), Box::pin(obj.body.unwrap())))
+	}
+
+	pub fn record_access(&self, object: &str) {
+		if (object.starts_with("blobs/")) {
+			self.accessed.lock().unwrap().insert(object.into(), unix_time(SystemTime::now()));
+		}
+	}
+
+	fn put_object_request(&self, object: &str, length: i64) -> PutObjectRequest {
+		PutObjectRequest {
+			bucket: self.bucket.to_string(),
+			key: object.into(),
+			content_length: Some(length),
+			server_side_encryption: self.options.server_side_encryption.map(|sse| sse.as_str().into()),
+			ssekms_key_id: self.options.sse_kms_key_id.clone(),
+			sse_customer_algorithm: self.options.sse_customer_algorithm(),
+			sse_customer_key: self.options.sse_customer_key(),
+			sse_customer_key_md5: self.options.sse_customer_key_md5(),
+			storage_class: self.options.storage_class(object),
+			..Default::default()
+		}
 	}
 
-	pub async fn write<S, E>(&self, object: &str, reader: S, length: i64) -> Result<(), super::Error>
+	pub async fn write<S, E>(&self, object: &str, reader: S, length: i64, origin: &Origin) -> Result<(), super::Error>
 	where
 		S: TryStream<Ok = Bytes, Error = E> + Unpin + Send + 'static,
 		E: std::error::Error + Send + Sync + 'static,
 		super::Error: From<E>
 	{
 		let req = PutObjectRequest {
-			bucket: self.bucket.to_string(),
-			key: object.into(),
-			content_length: Some(length),
 			body: Some(ByteStream::new(reader.map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e)))),
-			..Default::default()
+			tagging: self.options.tagging(origin),
+			..self.put_object_request(object, length)
 		};
 
 		if let Err(e) = self.inner.put_object(req).await {
@@ -201,23 +483,221 @@ impl Repository {
 		Ok(())
 	}
 
-	pub async fn delete_old_objects(&self, older_than: SystemTime, prefix: &str) -> Result<usize, super::Error> {
+	/// Deletes every object under `prefix`, in batches as large as S3 allows, and returns how many
+	/// were deleted
+	pub async fn delete_prefix(&self, prefix: &str) -> Result<usize, super::Error> {
+		const BATCH_SIZE: usize = 1000;
+
 		let mut count = 0;
+		let mut batch = Vec::with_capacity(BATCH_SIZE);
+		let mut stream = self.list_objects(prefix).await?;
+		loop {
+			let obj = stream.next().await.transpose()?;
+			let done = obj.is_none();
+			if let Some(key) = obj.and_then(|obj| obj.key) {
+				batch.push(ObjectIdentifier { key, version_id: None });
+			}
+			if (batch.len() == BATCH_SIZE || (done && !batch.is_empty())) {
+				count += self.delete_objects(std::mem::replace(&mut batch, Vec::with_capacity(BATCH_SIZE))).await?;
+			}
+			if (done) {
+				return Ok(count);
+			}
+		}
+	}
+
+	async fn delete_objects(&self, objects: Vec<ObjectIdentifier>) -> Result<usize, super::Error> {
+		let requested = objects.len();
+		let req = DeleteObjectsRequest {
+			bucket: self.bucket.to_string(),
+			delete: Delete { objects, quiet: Some(true) },
+			..Default::default()
+		};
+		let errors = self.inner.delete_objects(req).await?.errors.unwrap_or_default();
+		for error in errors.iter() {
+			warn!(object = ?error.key, code = ?error.code, message = ?error.message, "Failed to delete object");
+		}
+		Ok(requested - errors.len())
+	}
+
+	pub fn list(&self, prefix: &str) -> BoxStream<'static, Result<super::ObjectInfo, super::Error>> {
+		let this = self.clone();
+		let prefix = prefix.to_owned();
+		Box::pin(try_stream! {
+			let mut stream = this.list_objects(&prefix).await?;
+			while let Some(obj) = stream.next().await {
+				let obj = obj?;
+				let Some(key) = obj.key else {
+					continue;
+				};
+				let modified = obj.last_modified.and_then(|s| OffsetDateTime::parse(&s, &Rfc3339).ok()).unwrap_or(OffsetDateTime::UNIX_EPOCH);
+				let size = obj.size.unwrap_or_default().try_into().unwrap_or_default();
+				yield super::ObjectInfo { key, size, modified: modified.into() };
+			}
+		})
+	}
+
+	/// Returns what was aged out, and what's still there afterwards
+	pub async fn delete_old_objects(&self, older_than: SystemTime, prefix: &str, keep: &HashSet<String>, dry_run: bool) -> Result<(super::Removed, super::Removed), super::Error> {
+		let (mut removed, mut remaining) = (super::Removed::default(), super::Removed::default());
 		let mut stream = self.list_objects(prefix).await?;
 		while let Some(obj) = stream.next().await {
 			let obj = obj?;
 			let Some(key) = obj.key else {
 				continue;
 			};
+			let size = obj.size.unwrap_or_default().try_into().unwrap_or_default();
 			let modified = obj.last_modified.and_then(|s| OffsetDateTime::parse(&s, &Rfc3339).ok()).unwrap_or(OffsetDateTime::UNIX_EPOCH);
-			if (modified < older_than) {
+			if (modified >= older_than || keep.contains(&key)) {
+				remaining.add(size);
+				continue;
+			}
+			if (dry_run) {
+				remaining.add(size);
+			} else {
 				match self.delete(key.as_ref()).await {
 					Ok(_) => info!(object = key, "Aged out"),
-					Err(_) => continue
+					Err(_) => {
+						remaining.add(size);
+						continue;
+					}
+				};
+			}
+			removed.add(size);
+		}
+		Ok((removed, remaining))
+	}
+}
+
+impl Repository {
+	/// Merges the access times recorded by every replica, including what this one hasn't written
+	/// yet.  Also returns the maps of replicas that look like they've gone away, by key.
+	async fn load_access_times(&self) -> Result<(HashMap<String, u64>, Vec<(String, HashMap<String, u64>)>), super::Error> {
+		let mut merged = self.accessed.lock().unwrap().clone();
+		let mut abandoned = Vec::new();
+		let abandoned_before = SystemTime::now() - ABANDONED_ACCESS_INDEX_AGE;
+		let mut stream = self.list_objects(ACCESS_INDEX_PREFIX).await?;
+		while let Some(obj) = stream.next().await {
+			let obj = obj?;
+			let Some(key) = obj.key.filter(|key| *key != *self.access_index) else {
+				continue;
+			};
+			let modified = obj.last_modified.and_then(|s| OffsetDateTime::parse(&s, &Rfc3339).ok()).unwrap_or(OffsetDateTime::UNIX_EPOCH);
+			let body = match self.get_object(&key).await {
+				Ok(v) => v.body,
+				// Deleted by whichever replica evicted last
+				Err(RusotoError::Service(GetObjectError::NoSuchKey(_))) => continue,
+				Err(e) => return Err(e.into())
+			};
+			let times = match body {
+				Some(body) => serde_json::from_slice::<HashMap<String, u64>>(body.try_collect::<BytesMut>().await?.as_ref()).unwrap_or_else(|error| {
+					warn!(index = key, %error, "Access time index is corrupt; ignoring it");
+					HashMap::new()
+				}),
+				None => HashMap::new()
+			};
+			for (object, accessed) in times.iter() {
+				let entry = merged.entry(object.clone()).or_default();
+				*entry = (*accessed).max(*entry);
+			}
+			if (SystemTime::from(modified) < abandoned_before) {
+				abandoned.push((key, times));
+			}
+		}
+		Ok((merged, abandoned))
+	}
+
+	/// Writes out the access times this replica has recorded, so that eviction on any replica
+	/// takes them into account
+	pub async fn save_access_times(&self) -> Result<(), super::Error> {
+		let body = {
+			let accessed = self.accessed.lock().unwrap();
+			if (accessed.is_empty()) {
+				return Ok(());
+			}
+			serde_json::to_vec(&*accessed).unwrap()
+		};
+		let len = body.len().try_into().unwrap_or(i64::MAX);
+		let req = PutObjectRequest {
+			body: Some(body.into()),
+			..self.put_object_request(&self.access_index, len)
+		};
+		self.inner.put_object(req).await?;
+		Ok(())
+	}
+
+	/// Deletes the least-recently-accessed objects under `prefix`, other than those in `keep`, until
+	/// their total size is no more than `max_size` bytes.  Objects that have never been read are
+	/// treated as having been accessed when they were written.
+	pub async fn evict_to_size(&self, prefix: &str, max_size: u64, keep: &HashSet<String>, dry_run: bool) -> Result<super::Eviction, super::Error> {
+		let (index, abandoned) = self.load_access_times().await?;
+
+		let mut objects = Vec::new();
+		let mut remaining = HashSet::new();
+		let mut total = 0;
+		let mut stream = self.list_objects(prefix).await?;
+		while let Some(obj) = stream.next().await {
+			let obj = obj?;
+			let Some(key) = obj.key else {
+				continue;
+			};
+			let size = obj.size.unwrap_or_default().try_into().unwrap_or_default();
+			let modified = obj.last_modified.and_then(|s| OffsetDateTime::parse(&s, &Rfc3339).ok()).unwrap_or(OffsetDateTime::UNIX_EPOCH);
+			let accessed = index.get(&key).copied().unwrap_or_default().max(unix_time(modified.into()));
+			total += size;
+			if (keep.contains(&key)) {
+				remaining.insert(key);
+				continue;
+			}
+			objects.push((accessed, size, key));
+		}
+
+		let mut eviction = super::Eviction::default();
+		objects.sort_unstable_by_key(|(accessed, _, _)| *accessed);
+		let mut objects = objects.into_iter();
+		while (total > max_size) {
+			let Some((_, size, key)) = objects.next() else {
+				break;
+			};
+			if (dry_run) {
+				remaining.insert(key);
+			} else {
+				match self.delete(key.as_ref()).await {
+					Ok(_) => info!(object = key, "Evicted"),
+					Err(_) => {
+						remaining.insert(key);
+						continue;
+					}
 				};
-				count += 1;
+			}
+			total -= size;
+			eviction.count += 1;
+			eviction.bytes += size;
+		}
+		eviction.remaining = total;
+
+		if (dry_run) {
+			return Ok(eviction);
+		}
+		// Forget objects that were evicted, aged out, or deleted some other way, and take over what
+		// abandoned replicas recorded about the rest, so that their maps can be deleted
+		remaining.extend(objects.map(|(_, _, key)| key));
+		{
+			let mut accessed = self.accessed.lock().unwrap();
+			accessed.retain(|key, _| remaining.contains(key) || !key.starts_with(prefix));
+			for (object, time) in abandoned.iter().flat_map(|(_, times)| times.iter()) {
+				if (remaining.contains(object)) {
+					let entry = accessed.entry(object.clone()).or_default();
+					*entry = (*time).max(*entry);
+				}
+			}
+		}
+		self.save_access_times().await?;
+		for (key, _) in abandoned {
+			if let Err(error) = self.delete(&key).await {
+				warn!(index = key, %error, "Error deleting abandoned access time index");
 			}
 		}
-		Ok(count)
+		Ok(eviction)
 	}
 }
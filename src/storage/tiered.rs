@@ -0,0 +1,238 @@
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::AtomicU64;
+use core::sync::atomic::Ordering;
+use core::time::Duration;
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use actix_web::rt;
+use actix_web::web::Bytes;
+use bytesize::ByteSize;
+use clap::Parser;
+use futures::stream::BoxStream;
+use futures::stream::StreamExt;
+use futures::stream::TryStream;
+use futures::stream::TryStreamExt;
+use tracing::error;
+use tracing::info;
+use tracing::warn;
+
+use super::filesystem;
+use super::s3;
+use super::Origin;
+use super::ReadStream;
+
+#[derive(Clone, Debug, Parser)]
+pub struct Config {
+	#[clap(flatten)]
+	filesystem: filesystem::Config,
+	#[clap(flatten)]
+	s3: s3::Config,
+	/// Maximum total size of the local cache; when exceeded, the least-recently-accessed objects
+	/// will be evicted from local storage.  They will remain in S3.
+	#[clap(env = "TIERED_LOCAL_MAX_SIZE", long)]
+	local_max_size: ByteSize
+}
+
+impl Config {
+	pub fn repository(&self) -> Result<Repository, super::Error> {
+		Ok(Repository {
+			local: self.filesystem.repository(),
+			remote: self.s3.repository()?,
+			local_max_size: self.local_max_size.as_u64(),
+			local_size: Arc::new(AtomicU64::new(0)),
+			measured: Arc::new(AtomicBool::new(false)),
+			evicting: Arc::new(AtomicBool::new(false))
+		})
+	}
+}
+
+/// A bounded local filesystem cache in front of S3.  S3 is authoritative; the local copy of an
+/// object keeps the modification time of the S3 object it was copied from, so aging works the
+/// same in both tiers.
+#[derive(Clone)]
+pub struct Repository {
+	local: filesystem::Repository,
+	remote: s3::Repository,
+	local_max_size: u64,
+	local_size: Arc<AtomicU64>,
+	/// Whether `local_size` includes what was already in local storage at startup, which isn't
+	/// known until the first eviction walk
+	measured: Arc<AtomicBool>,
+	evicting: Arc<AtomicBool>
+}
+
+impl Repository {
+	pub async fn read(&self, object: &str, invalidation: Duration) -> Result<ReadStream, super::Error> {
+		match self.local.read(object.into(), invalidation).await {
+			Ok(v) => {
+				// Keep S3's notion of what's in use accurate, so that eviction there doesn't remove
+				// anything that's only being served from local storage
+				self.remote.record_access(object);
+				return Ok(v);
+			},
+			Err(e) if e.is_not_found() => (),
+			Err(error) => warn!(object, %error, "Failed to read from local cache")
+		};
+
+		let stream = self.remote.read(object, invalidation).await?;
+		let (length, modified) = (stream.length(), stream.modified());
+		let (tx, rx) = async_broadcast::broadcast(16);
+		{
+			let mut stream = stream.into_inner().err_into::<super::Error>();
+			rt::spawn(async move {
+				while let Some(chunk) = stream.next().await {
+					let is_err = chunk.is_err();
+					if (tx.broadcast(chunk).await.is_err() || is_err) {
+						return;
+					}
+				}
+			});
+		}
+
+		{
+			let rx = rx.clone();
+			let this = self.clone();
+			let object = object.to_owned();
+			rt::spawn(async move {
+				if let Err(error) = this.local.write(object.as_str().into(), rx).await {
+					warn!(object, %error, "Failed to populate local cache");
+					return;
+				}
+				if let Err(error) = this.local.set_modified(object.as_str().into(), modified).await {
+					warn!(object, %error, "Failed to set modification time in local cache");
+				}
+				this.track_local_write(length);
+			});
+		}
+
+		Ok(ReadStream::new(length, modified, Box::pin(rx.map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e)))))
+	}
+
+	/// Reads the local copy of an object if there is one, or the S3 copy otherwise, regardless of
+	/// its age, without recording an access or populating the local cache
+	pub async fn read_unrecorded(&self, object: &str) -> Result<ReadStream, super::Error> {
+		match self.local.read_unrecorded(object.into()).await {
+			Ok(v) => return Ok(v),
+			Err(e) if e.is_not_found() => (),
+			Err(error) => warn!(object, %error, "Failed to read from local cache")
+		};
+		self.remote.read_unrecorded(object).await
+	}
+
+	pub async fn read_shared(&self, object: &str) -> Result<ReadStream, super::Error> {
+		self.remote.read_unrecorded(object).await
+	}
+
+	pub async fn write<S, E>(&self, object: &str, mut reader: S, length: i64, origin: &Origin) -> Result<(), super::Error>
+	where
+		S: TryStream<Ok = Bytes, Error = E> + Unpin + Send + 'static,
+		E: std::error::Error + Send + Sync + 'static,
+		super::Error: From<E>
+	{
+		let (tx, rx) = async_broadcast::broadcast::<Result<Bytes, super::Error>>(16);
+		let pump = async move {
+			while let Some(chunk) = reader.try_next().await.map_err(super::Error::from).transpose() {
+				let is_err = chunk.is_err();
+				if (tx.broadcast(chunk).await.is_err() || is_err) {
+					return;
+				}
+			}
+		};
+		let (_, local, remote) = futures::join!(pump, self.local.write(object.into(), rx.clone()), self.remote.write(object, rx, length, origin));
+		remote?;
+		match local {
+			Ok(_) => self.track_local_write(length.try_into().unwrap_or_default()),
+			Err(error) => warn!(object, %error, "Failed to write to local cache")
+		};
+		Ok(())
+	}
+
+	pub async fn delete(&self, object: &str) -> Result<(), super::Error> {
+		match self.local.delete_object(object.into()).await {
+			Ok(_) => (),
+			Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+			Err(error) => warn!(object, %error, "Failed to delete from local cache")
+		};
+		self.remote.delete(object).await?;
+		Ok(())
+	}
+
+	pub async fn delete_prefix(&self, prefix: &str) -> Result<usize, super::Error> {
+		if let Err(error) = self.local.delete_prefix(prefix.into()).await {
+			warn!(prefix, %error, "Failed to delete from local cache");
+		}
+		self.remote.delete_prefix(prefix).await
+	}
+
+	pub fn list(&self, prefix: &str) -> BoxStream<'static, Result<super::ObjectInfo, super::Error>> {
+		self.remote.list(prefix)
+	}
+
+	pub fn local(&self) -> &filesystem::Repository {
+		&self.local
+	}
+
+	pub fn remote(&self) -> &s3::Repository {
+		&self.remote
+	}
+
+	pub async fn evict_to_size(&self, prefix: &str, max_size: u64, keep: &HashSet<String>, dry_run: bool) -> Result<super::Eviction, super::Error> {
+		self.remote.evict_to_size(prefix, max_size, keep, dry_run).await
+	}
+
+	pub async fn save_access_times(&self) -> Result<(), super::Error> {
+		self.remote.save_access_times().await
+	}
+
+	pub async fn presigned_url(&self, object: &str, invalidation: Duration, expires_in: Duration) -> Result<Option<String>, super::Error> {
+		self.remote.presigned_url(object, invalidation, expires_in).await
+	}
+
+	/// Returns what was aged out, and what's still there afterwards in S3, which has everything
+	pub async fn delete_old_objects(&self, older_than: SystemTime, prefix: &str, keep: &HashSet<String>, dry_run: bool) -> Result<(super::Removed, super::Removed), super::Error> {
+		let (local, _) = self.local.delete_old_files(older_than, prefix.into(), keep, dry_run).await?;
+		let (remote, remaining) = self.remote.delete_old_objects(older_than, prefix, keep, dry_run).await?;
+		// Most of what's aged out locally will also have aged out remotely, so this is the most
+		// meaningful count without double-counting
+		let removed = match remote.count >= local.count {
+			true => remote,
+			false => local
+		};
+		Ok((removed, remaining))
+	}
+
+	fn track_local_write(&self, length: u64) {
+		let size = self.local_size.fetch_add(length, Ordering::Relaxed) + length;
+		// The first write since startup triggers a walk regardless, to count what was already there
+		let measured = self.measured.load(Ordering::Acquire);
+		if ((measured && size <= self.local_max_size) || self.evicting.swap(true, Ordering::AcqRel)) {
+			return;
+		}
+		let this = self.clone();
+		rt::spawn(async move {
+			this.evict_local().await;
+			this.evicting.store(false, Ordering::Release);
+		});
+	}
+
+	pub async fn delete_temp_files(&self, prefix: &str) {
+		self.local.delete_temp_files(prefix.as_ref()).await
+	}
+
+	/// Evicts down to 90% of the maximum size, so that we aren't evicting on every write once the
+	/// cache is full.  Pinned objects may be evicted from local storage; they remain in S3.
+	pub async fn evict_local(&self) {
+		match self.local.evict_to_size("".into(), self.local_max_size / 10 * 9, &HashSet::new(), false).await {
+			Ok(eviction) => {
+				self.local_size.store(eviction.remaining, Ordering::Relaxed);
+				self.measured.store(true, Ordering::Release);
+				if (eviction.count > 0) {
+					info!(count = eviction.count, size = eviction.remaining, "Evicted objects from local cache");
+				}
+			},
+			Err(error) => error!(%error, "Error evicting objects from local cache")
+		}
+	}
+}
@@ -0,0 +1,125 @@
+use actix_web::dev::ServiceRequest;
+use actix_web::http::header::HeaderMap;
+use opentelemetry::global;
+use opentelemetry::propagation::Extractor;
+use opentelemetry::KeyValue;
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::Tracer;
+use opentelemetry_sdk::Resource;
+use tracing::error;
+use tracing::info_span;
+use tracing::Span;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::EnvFilter;
+use tracing_subscriber::Layer;
+
+/// Sets up logging, filtered by `RUST_LOG`, and, if an OTLP endpoint is configured through the
+/// standard `OTEL_EXPORTER_OTLP_ENDPOINT` or `OTEL_EXPORTER_OTLP_TRACES_ENDPOINT` environment
+/// variables, export of spans at info level and above.  Everything else about the export (headers,
+/// timeout, sampling, resource attributes) is configured by the other standard `OTEL_*` variables.
+/// Logs go to stdout, unless `stderr` is set, e.g. because stdout is for something else.  Must be
+/// called from within the async runtime.
+pub fn init(stderr: bool) {
+	let (tracer, error) = match exporting() {
+		true => match tracer() {
+			Ok(v) => (Some(v), None),
+			Err(e) => (None, Some(e))
+		},
+		false => (None, None)
+	};
+	let writer = match stderr {
+		true => BoxMakeWriter::new(std::io::stderr),
+		false => BoxMakeWriter::new(std::io::stdout)
+	};
+	tracing_subscriber::registry()
+		.with(tracing_subscriber::fmt::layer().compact().with_writer(writer).with_filter(EnvFilter::from_default_env()))
+		.with(tracer.map(|tracer| tracing_opentelemetry::layer().with_tracer(tracer).with_filter(LevelFilter::INFO)))
+		.init();
+	if let Some(error) = error {
+		error!(%error, "Error setting up trace export; traces won't be exported");
+	}
+}
+
+/// Flushes any spans that haven't been exported yet
+pub fn shutdown() {
+	if (exporting()) {
+		global::shutdown_tracer_provider();
+	}
+}
+
+fn exporting() -> bool {
+	let set = |var| std::env::var_os(var).map_or(false, |v| !v.is_empty());
+	let disabled = std::env::var("OTEL_SDK_DISABLED").map_or(false, |v| v.eq_ignore_ascii_case("true"));
+	!disabled && (set("OTEL_EXPORTER_OTLP_ENDPOINT") || set("OTEL_EXPORTER_OTLP_TRACES_ENDPOINT"))
+}
+
+fn tracer() -> Result<Tracer, opentelemetry::trace::TraceError> {
+	global::set_text_map_propagator(TraceContextPropagator::new());
+	// Resource::default() reads OTEL_SERVICE_NAME and OTEL_RESOURCE_ATTRIBUTES, but falls back to
+	// "unknown_service" rather than something useful
+	let service_name = std::env::var("OTEL_SERVICE_NAME").unwrap_or_else(|_| env!("CARGO_PKG_NAME").to_owned());
+	let resource = Resource::default().merge(&Resource::new([KeyValue::new("service.name", service_name)]));
+	opentelemetry_otlp::new_pipeline()
+		.tracing()
+		// The endpoint is left for the exporter to read from the environment
+		.with_exporter(opentelemetry_otlp::new_exporter().http().with_env())
+		.with_trace_config(opentelemetry_sdk::trace::config().with_resource(resource))
+		// actix runs a single-threaded runtime, on which shutting down the batch exporter would
+		// deadlock; this runs the exporter on a thread of its own
+		.install_batch(opentelemetry_sdk::runtime::TokioCurrentThread)
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+	fn get(&self, key: &str) -> Option<&str> {
+		self.0.get(key).and_then(|v| v.to_str().ok())
+	}
+
+	fn keys(&self) -> Vec<&str> {
+		self.0.keys().map(|k| k.as_str()).collect()
+	}
+}
+
+/// A span covering the handling of `req`, continuing the trace from its `traceparent` header if it
+/// has one
+pub fn request_span(req: &ServiceRequest) -> Span {
+	let route = req.match_pattern().unwrap_or_else(|| req.path().to_owned());
+	let span = info_span!(
+		"request",
+		otel.name = format!("{} {}", req.method(), route),
+		otel.kind = "server",
+		http.method = %req.method(),
+		http.target = req.path(),
+		http.status_code = tracing::field::Empty
+	);
+	span.set_parent(global::get_text_map_propagator(|propagator| propagator.extract(&HeaderExtractor(req.headers()))));
+	span
+}
+
+#[cfg(test)]
+mod tests {
+	use actix_web::http::header::HeaderName;
+	use actix_web::http::header::HeaderValue;
+	use opentelemetry::propagation::TextMapPropagator;
+	use opentelemetry::trace::TraceContextExt;
+
+	use super::*;
+
+	#[test]
+	fn extract_trace_context() {
+		let mut headers = HeaderMap::new();
+		headers.insert(HeaderName::from_static("traceparent"), HeaderValue::from_static("00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01"));
+		let context = TraceContextPropagator::new().extract(&HeaderExtractor(&headers));
+		let span = context.span();
+		let span_context = span.span_context();
+		assert!(span_context.is_remote());
+		assert_eq!(span_context.trace_id().to_string(), "0af7651916cd43dd8448eb211c80319c");
+		assert_eq!(span_context.span_id().to_string(), "b7ad6b7169203331");
+	}
+}
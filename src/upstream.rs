@@ -1,4 +1,5 @@
 use std::collections::HashMap;
+use std::time::Instant;
 
 use camino::Utf8PathBuf;
 use clap::Parser;
@@ -10,16 +11,95 @@ use serde::Deserialize;
 use serde_with::serde_as;
 use serde_with::DisplayFromStr;
 use tokio::fs::read_to_string;
+use tokio::sync::Mutex;
 use tracing::info;
 use tracing::warn;
 
+use crate::manifest::Platform;
 use crate::util::SecretString;
 
+mod credentials;
+
+/// How long to put off fetching credentials again after starting to, so that requests in the
+/// meantime don't all do the same, or after failing to
+const CREDENTIALS_RETRY: core::time::Duration = core::time::Duration::from_secs(60);
+
 #[derive(Clone, Debug)]
 pub struct Client {
 	pub client: InnerClient,
 	pub manifest_invalidation_time: core::time::Duration,
-	pub blob_invalidation_time: core::time::Duration
+	pub blob_invalidation_time: core::time::Duration,
+	/// Whether to pull everything referenced by a manifest in the background after pulling the
+	/// manifest itself from upstream
+	pub prefetch: bool,
+	/// The platforms that matter in this namespace; children of indexes for other platforms are
+	/// ignored by prefetching, pinning, and garbage collection.  Empty if all of them matter.
+	pub platforms: Vec<Platform>,
+	/// Whether to remove children for other platforms from indexes when serving them
+	pub filter_indexes: bool,
+	redirect_blobs: Option<core::time::Duration>,
+	no_redirect_user_agents: Vec<CompactString>,
+	/// What this was built from, so that it can be rebuilt with fresh credentials
+	config: SingleUpstreamConfig,
+	/// When the credentials need to be fetched again, if they came from a Docker config file or
+	/// credential helper
+	credentials_expire: Option<Instant>
+}
+
+impl Client {
+	/// Builds a client, fetching its credentials from a Docker config file or credential helper
+	/// if it's configured to and wasn't given a username or password
+	async fn build(config: SingleUpstreamConfig) -> Result<Self, ConfigError> {
+		let credentials = match (&config.username, &config.password) {
+			(None, None) => credentials::fetch(&config.host, config.docker_config.as_deref(), config.credential_helper.as_deref()).await?,
+			_ => None
+		};
+		let expires = credentials.is_some().then(|| Instant::now() + *config.credential_lifetime);
+		let (username, password) = match credentials {
+			Some(credentials) => (Some(credentials.username), Some(credentials.password)),
+			None => (config.username.clone(), config.password.clone())
+		};
+		Ok(Self::new(config, username, password, expires)?)
+	}
+
+	fn new(config: SingleUpstreamConfig, username: Option<SecretString>, password: Option<SecretString>, credentials_expire: Option<Instant>) -> Result<Self, Error> {
+		let client = InnerClient::configure()
+			.registry(&config.host)
+			.insecure_registry(!config.tls)
+			.accept_invalid_certs(config.accept_invalid_certs)
+			.user_agent(config.user_agent.clone())
+			.username(username.map(|s| s.into_inner()))
+			.password(password.map(|s| s.into_inner()))
+			.build()?;
+		Ok(Self {
+			client,
+			manifest_invalidation_time: config.manifest_invalidation_time.into(),
+			blob_invalidation_time: config.blob_invalidation_time.into(),
+			prefetch: config.prefetch,
+			platforms: config.platforms.clone(),
+			filter_indexes: config.filter_indexes,
+			redirect_blobs: config.redirect_blobs.map(Into::into),
+			no_redirect_user_agents: config.no_redirect_user_agents.clone(),
+			config,
+			credentials_expire
+		})
+	}
+
+	/// Whether the credentials came from a Docker config file or credential helper, and so can be
+	/// fetched again if upstream rejects them
+	pub fn refreshes_credentials(&self) -> bool {
+		self.credentials_expire.is_some()
+	}
+
+	/// If blob cache hits should be served as a redirect to the storage backend, returns how long
+	/// the redirect URL should be valid for
+	pub fn redirect_blobs(&self, user_agent: Option<&str>) -> Option<core::time::Duration> {
+		let user_agent = user_agent.unwrap_or_default();
+		match self.no_redirect_user_agents.iter().any(|s| user_agent.contains(s.as_str())) {
+			true => None,
+			false => self.redirect_blobs
+		}
+	}
 }
 
 pub struct Clients(HashMap<CompactString, Client>);
@@ -37,16 +117,66 @@ impl Clients {
 		Ok(())
 	}
 
+	/// Every configured upstream, by namespace, leaving out the default
+	pub fn iter(&self) -> impl Iterator<Item = (&CompactString, &Client)> {
+		self.0.iter().filter(|(ns, _)| !ns.is_empty())
+	}
+
+	/// Fetches the credentials for `namespace` again, if they came from a Docker config file or
+	/// credential helper and either they've expired or `force` is set, and rebuilds its client with
+	/// them.  `clients` isn't locked while they're fetched, since a credential helper can take a
+	/// while.  If that fails, the old credentials are kept, and fetching them again is put off for
+	/// a minute.
+	pub async fn refresh_credentials(clients: &Mutex<Self>, namespace: &str, force: bool) -> Result<(), ConfigError> {
+		let Some(config) = clients.lock().await.start_refresh(namespace, force) else {
+			return Ok(());
+		};
+		let fresh = Client::build(config).await?;
+		info!(namespace = fresh.config.namespace.as_str(), "Fetched fresh upstream credentials");
+		clients.lock().await.replace(fresh);
+		Ok(())
+	}
+
+	/// What to build `namespace`'s client from, if its credentials are due to be fetched again,
+	/// in which case doing so again is put off for now
+	fn start_refresh(&mut self, namespace: &str, force: bool) -> Option<SingleUpstreamConfig> {
+		let client = self.0.get(namespace)?;
+		match client.credentials_expire {
+			Some(expires) if force || expires <= Instant::now() => (),
+			_ => return None
+		};
+		let config = client.config.clone();
+		let retry = Instant::now() + CREDENTIALS_RETRY;
+		for client in self.0.values_mut().filter(|client| client.config.namespace == config.namespace) {
+			client.credentials_expire = Some(retry);
+		}
+		Some(config)
+	}
+
+	/// Puts `fresh` in place of the client it was rebuilt from, both under its own namespace and,
+	/// if it's the default, under the empty one used for requests without a namespace
+	fn replace(&mut self, fresh: Client) {
+		for client in self.0.values_mut().filter(|client| client.config.namespace == fresh.config.namespace) {
+			*client = fresh.clone();
+		}
+	}
+
 	pub fn invalidation_config(&self) -> InvalidationConfig {
 		let mut config = InvalidationConfig {
 			blob: core::time::Duration::from_secs(10),
-			manifests: HashMap::with_capacity(self.0.len())
+			blobs: HashMap::with_capacity(self.0.len()),
+			manifests: HashMap::with_capacity(self.0.len()),
+			platforms: HashMap::new()
 		};
 		for (ns, client) in self.0.iter() {
 			if (ns.is_empty()) {
 				continue;
 			}
+			config.blobs.insert(ns.clone(), client.blob_invalidation_time);
 			config.manifests.insert(ns.clone(), client.manifest_invalidation_time);
+			if (!client.platforms.is_empty()) {
+				config.platforms.insert(ns.clone(), client.platforms.clone());
+			}
 			if (client.blob_invalidation_time > config.blob) {
 				config.blob = client.blob_invalidation_time;
 			}
@@ -63,8 +193,14 @@ impl FromIterator<(CompactString, Client)> for Clients {
 
 #[derive(Clone, Debug)]
 pub struct InvalidationConfig {
+	/// The longest blob invalidation time of any namespace; used for blobs that can't be
+	/// attributed to a namespace
 	pub blob: core::time::Duration,
-	pub manifests: HashMap<CompactString, core::time::Duration>
+	pub blobs: HashMap<CompactString, core::time::Duration>,
+	pub manifests: HashMap<CompactString, core::time::Duration>,
+	/// Namespaces that only care about some platforms; children of indexes for other platforms
+	/// aren't considered to be referenced
+	pub platforms: HashMap<CompactString, Vec<Platform>>
 }
 
 const fn truth() -> bool {
@@ -79,6 +215,10 @@ fn default_blob_invalidation_time() -> Duration {
 	core::time::Duration::from_secs(14 * 86400).into()
 }
 
+fn default_credential_lifetime() -> Duration {
+	core::time::Duration::from_secs(3600).into()
+}
+
 #[serde_as]
 #[derive(Clone, Debug, Deserialize)]
 pub struct SingleUpstreamConfig {
@@ -94,12 +234,31 @@ pub struct SingleUpstreamConfig {
 	username: Option<SecretString>,
 	#[serde(default)]
 	password: Option<SecretString>,
+	#[serde(default)]
+	docker_config: Option<Utf8PathBuf>,
+	#[serde(default)]
+	credential_helper: Option<CompactString>,
+	#[serde(default = "default_credential_lifetime")]
+	#[serde_as(as = "DisplayFromStr")]
+	credential_lifetime: Duration,
 	#[serde(default = "default_manifest_invalidation_time")]
 	#[serde_as(as = "DisplayFromStr")]
 	manifest_invalidation_time: Duration,
 	#[serde(default = "default_blob_invalidation_time")]
 	#[serde_as(as = "DisplayFromStr")]
-	blob_invalidation_time: Duration
+	blob_invalidation_time: Duration,
+	#[serde(default)]
+	#[serde_as(as = "Option<DisplayFromStr>")]
+	redirect_blobs: Option<Duration>,
+	#[serde(default)]
+	no_redirect_user_agents: Vec<CompactString>,
+	#[serde(default)]
+	prefetch: bool,
+	#[serde(default)]
+	#[serde_as(as = "Vec<DisplayFromStr>")]
+	platforms: Vec<Platform>,
+	#[serde(default)]
+	filter_indexes: bool
 }
 
 impl SingleUpstreamConfig {
@@ -116,8 +275,16 @@ impl SingleUpstreamConfig {
 			user_agent: None,
 			username: None,
 			password: None,
+			docker_config: None,
+			credential_helper: None,
+			credential_lifetime: default_credential_lifetime(),
 			manifest_invalidation_time: default_manifest_invalidation_time(),
-			blob_invalidation_time: default_blob_invalidation_time()
+			blob_invalidation_time: default_blob_invalidation_time(),
+			redirect_blobs: None,
+			no_redirect_user_agents: Vec::new(),
+			prefetch: false,
+			platforms: Vec::new(),
+			filter_indexes: false
 		}
 	}
 }
@@ -126,19 +293,8 @@ impl TryFrom<SingleUpstreamConfig> for Client {
 	type Error = Error;
 
 	fn try_from(config: SingleUpstreamConfig) -> Result<Self, Self::Error> {
-		let client = InnerClient::configure()
-			.registry(&config.host)
-			.insecure_registry(!config.tls)
-			.accept_invalid_certs(config.accept_invalid_certs)
-			.user_agent(config.user_agent)
-			.username(config.username.map(|s| s.into_inner()))
-			.password(config.password.map(|s| s.into_inner()))
-			.build()?;
-		Ok(Self {
-			client,
-			manifest_invalidation_time: config.manifest_invalidation_time.into(),
-			blob_invalidation_time: config.blob_invalidation_time.into()
-		})
+		let (username, password) = (config.username.clone(), config.password.clone());
+		Self::new(config, username, password, None)
 	}
 }
 
@@ -161,7 +317,24 @@ pub struct UpstreamConfig {
 	///
 	/// Example: `{"docker.io": {"username": "foo", "password": "bar"}, "namespace2": {"username":
 	/// {"aaa", "pasword": "bbb"}}`
-	upstream_credentials: String
+	upstream_credentials: String,
+	/// From the `upstreams` section of --config-file; --upstream-config-file takes precedence
+	#[clap(skip)]
+	upstreams: Option<Vec<SingleUpstreamConfig>>
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ConfigError {
+	#[error("Failed to read {0}: {1}")]
+	Read(Utf8PathBuf, std::io::Error),
+	#[error("Invalid upstream config in {0}: {1}")]
+	Parse(Utf8PathBuf, serde_yaml::Error),
+	#[error("Invalid UPSTREAM_CREDENTIALS: {0}")]
+	Credentials(#[from] serde_json::Error),
+	#[error("Failed to get upstream credentials: {0}")]
+	Fetch(#[from] credentials::Error),
+	#[error("Failed to configure upstream: {0}")]
+	Client(#[from] Error)
 }
 
 #[derive(Debug, Deserialize)]
@@ -171,13 +344,25 @@ pub struct CredentialsOverride<'a> {
 }
 
 impl UpstreamConfig {
-	pub async fn clients(&self) -> Result<Clients, Error> {
-		let mut upstream_credentials: HashMap<&str, CredentialsOverride<'_>> = serde_json::from_str(self.upstream_credentials.as_ref()).unwrap();
-		let mut clients = match self.upstream_config_file.as_ref() {
-			Some(file) => {
-				let upstream_config = read_to_string(file).await.unwrap();
-				let upstream_config: Vec<SingleUpstreamConfig> = serde_yaml::from_str(&upstream_config).unwrap();
-				upstream_config
+	pub fn set_upstreams(&mut self, upstreams: Option<Vec<SingleUpstreamConfig>>) {
+		self.upstreams = upstreams;
+	}
+
+	async fn upstreams(&self) -> Result<Option<Vec<SingleUpstreamConfig>>, ConfigError> {
+		let Some(file) = self.upstream_config_file.as_ref() else {
+			return Ok(self.upstreams.clone());
+		};
+		let upstream_config = read_to_string(file).await.map_err(|e| ConfigError::Read(file.clone(), e))?;
+		let upstream_config = serde_yaml::from_str(&upstream_config).map_err(|e| ConfigError::Parse(file.clone(), e))?;
+		Ok(Some(upstream_config))
+	}
+
+	pub async fn clients(&self) -> Result<Clients, ConfigError> {
+		let mut upstream_credentials: HashMap<&str, CredentialsOverride<'_>> = serde_json::from_str(self.upstream_credentials.as_ref())?;
+		let mut clients = match self.upstreams().await? {
+			Some(upstream_config) => {
+				let mut clients = HashMap::with_capacity(upstream_config.len());
+				let upstream_config = upstream_config
 					.into_iter()
 					.map(|conf| {
 						info!(config = ?conf, "Parsed upstream config");
@@ -194,9 +379,12 @@ impl UpstreamConfig {
 							conf
 						},
 						None => conf
-					})
-					.map(|conf| Ok::<_, Error>((conf.namespace.clone(), conf.try_into()?)))
-					.collect::<Result<Clients, _>>()?
+					});
+				// Credentials may need to be read from files or helpers, so these are built one by one
+				for conf in upstream_config {
+					clients.insert(conf.namespace.clone(), Client::build(conf).await?);
+				}
+				Clients(clients)
 			},
 			None => {
 				let (username, password) = match upstream_credentials.remove("docker.io") {
@@ -212,10 +400,18 @@ impl UpstreamConfig {
 					user_agent: None,
 					username,
 					password,
+					docker_config: None,
+					credential_helper: None,
+					credential_lifetime: default_credential_lifetime(),
 					manifest_invalidation_time: default_manifest_invalidation_time(),
-					blob_invalidation_time: default_blob_invalidation_time()
+					blob_invalidation_time: default_blob_invalidation_time(),
+					redirect_blobs: None,
+					no_redirect_user_agents: Vec::new(),
+					prefetch: false,
+					platforms: Vec::new(),
+					filter_indexes: false
 				}.try_into()?;
-				let mut map = HashMap::with_capacity(1);
+				let mut map: HashMap<CompactString, Client> = HashMap::with_capacity(1);
 				map.insert("docker.io".into(), client);
 				Clients(map)
 			}
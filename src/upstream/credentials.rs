@@ -0,0 +1,199 @@
+use std::collections::HashMap;
+use std::process::Stdio;
+use std::time::Duration;
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use camino::Utf8Path;
+use camino::Utf8PathBuf;
+use compact_str::CompactString;
+use serde::Deserialize;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+
+use crate::util::SecretString;
+
+/// How long a credential helper gets to answer
+const HELPER_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// What credential helpers return, and Docker config files hold, as the username when the secret is
+/// an identity token rather than a password
+const IDENTITY_TOKEN_USERNAME: &str = "<token>";
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+	#[error("Failed to read {0}: {1}")]
+	Read(Utf8PathBuf, std::io::Error),
+	#[error("Invalid Docker config {0}: {1}")]
+	Parse(Utf8PathBuf, serde_json::Error),
+	#[error("Invalid auth for {0} in {1}")]
+	InvalidAuth(CompactString, Utf8PathBuf),
+	#[error("Failed to run docker-credential-{0}: {1}")]
+	Helper(CompactString, std::io::Error),
+	#[error("docker-credential-{0} timed out")]
+	HelperTimeout(CompactString),
+	#[error("docker-credential-{0} failed: {1}")]
+	HelperFailed(CompactString, String),
+	#[error("Invalid output from docker-credential-{0}: {1}")]
+	HelperOutput(CompactString, serde_json::Error)
+}
+
+#[derive(Clone, Debug)]
+pub struct Credentials {
+	pub(crate) username: SecretString,
+	pub(crate) password: SecretString
+}
+
+impl Credentials {
+	/// Identity tokens are sent as the password, which works with registries that accept them
+	/// for basic authentication against their token endpoint (e.g. ACR), but not with those that
+	/// only accept them through OAuth2's refresh token grant
+	fn new(username: Option<&str>, password: Option<&str>, identity_token: Option<&str>) -> Self {
+		let username = username.filter(|s| !s.is_empty()).unwrap_or(IDENTITY_TOKEN_USERNAME);
+		let password = identity_token.filter(|s| !s.is_empty()).or(password).unwrap_or_default();
+		Self { username: username.into(), password: password.into() }
+	}
+}
+
+/// The parts of `~/.docker/config.json` that say where credentials are
+#[derive(Debug, Default, Deserialize)]
+struct DockerConfig {
+	#[serde(default)]
+	auths: HashMap<String, Auth>,
+	#[serde(default, rename = "credHelpers")]
+	cred_helpers: HashMap<String, CompactString>,
+	#[serde(default, rename = "credsStore")]
+	creds_store: Option<CompactString>
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct Auth {
+	#[serde(default)]
+	auth: Option<String>,
+	#[serde(default)]
+	username: Option<String>,
+	#[serde(default)]
+	password: Option<String>,
+	#[serde(default)]
+	identitytoken: Option<String>
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct HelperOutput {
+	username: String,
+	secret: String
+}
+
+/// Docker config files and credential helpers know Docker Hub by its old index URL, and others by
+/// host, with or without a scheme and path
+fn registry_key(server: &str) -> &str {
+	let host = server.split_once("://").map_or(server, |(_, rest)| rest);
+	let host = host.split('/').next().unwrap_or_default();
+	match host {
+		"docker.io" | "registry-1.docker.io" | "index.docker.io" => "index.docker.io",
+		host => host
+	}
+}
+
+/// What to ask credential helpers for
+fn server_url(host: &str) -> &str {
+	match registry_key(host) {
+		"index.docker.io" => "https://index.docker.io/v1/",
+		_ => host
+	}
+}
+
+/// Gets credentials for `host` from `helper` if given, otherwise from `docker_config`, either from
+/// its `auths` or through the helper it names for `host`.  `None` if neither has any.
+pub async fn fetch(host: &str, docker_config: Option<&Utf8Path>, helper: Option<&str>) -> Result<Option<Credentials>, Error> {
+	if let Some(helper) = helper {
+		return run_helper(helper, host).await.map(Some);
+	}
+	let Some(path) = docker_config else {
+		return Ok(None);
+	};
+	let config = tokio::fs::read(path).await.map_err(|e| Error::Read(path.to_owned(), e))?;
+	let config: DockerConfig = serde_json::from_slice(&config).map_err(|e| Error::Parse(path.to_owned(), e))?;
+	let key = registry_key(host);
+	if let Some(helper) = config.cred_helpers.iter().find(|(server, _)| registry_key(server) == key).map(|(_, helper)| helper) {
+		return run_helper(helper, host).await.map(Some);
+	}
+	if let Some(auth) = config.auths.iter().find(|(server, _)| registry_key(server) == key).map(|(_, auth)| auth) {
+		return decode(auth).map(Some).ok_or_else(|| Error::InvalidAuth(key.into(), path.to_owned()));
+	}
+	match config.creds_store {
+		Some(helper) => run_helper(&helper, host).await.map(Some),
+		None => Ok(None)
+	}
+}
+
+/// `auth` is the base64 encoding of `username:password`, and takes precedence over the separate
+/// fields; with an identity token, the password is empty
+fn decode(auth: &Auth) -> Option<Credentials> {
+	let decoded = match auth.auth.as_deref().filter(|s| !s.is_empty()) {
+		Some(encoded) => Some(String::from_utf8(BASE64.decode(encoded).ok()?).ok()?),
+		None => None
+	};
+	let (username, password) = match decoded.as_deref() {
+		Some(decoded) => decoded.split_once(':').map(|(u, p)| (Some(u), Some(p)))?,
+		None => (auth.username.as_deref(), auth.password.as_deref())
+	};
+	Some(Credentials::new(username, password, auth.identitytoken.as_deref()))
+}
+
+/// Runs `docker-credential-<helper> get`, as Docker would
+async fn run_helper(helper: &str, host: &str) -> Result<Credentials, Error> {
+	let mut child = Command::new(format!("docker-credential-{helper}"))
+		.arg("get")
+		.stdin(Stdio::piped())
+		.stdout(Stdio::piped())
+		.stderr(Stdio::piped())
+		.kill_on_drop(true)
+		.spawn()
+		.map_err(|e| Error::Helper(helper.into(), e))?;
+	{
+		// Dropped at the end of this block, closing its stdin so that it knows the request is over
+		let mut stdin = child.stdin.take().unwrap();
+		stdin.write_all(server_url(host).as_bytes()).await.map_err(|e| Error::Helper(helper.into(), e))?;
+	}
+	let output = match tokio::time::timeout(HELPER_TIMEOUT, child.wait_with_output()).await {
+		Ok(result) => result.map_err(|e| Error::Helper(helper.into(), e))?,
+		Err(_) => return Err(Error::HelperTimeout(helper.into()))
+	};
+	if (!output.status.success()) {
+		// Helpers report errors like "credentials not found in native keychain" on stdout
+		let message = [output.stdout, output.stderr].concat();
+		return Err(Error::HelperFailed(helper.into(), String::from_utf8_lossy(&message).trim().to_owned()));
+	}
+	let output: HelperOutput = serde_json::from_slice(&output.stdout).map_err(|e| Error::HelperOutput(helper.into(), e))?;
+	Ok(Credentials::new(Some(&output.username), Some(&output.secret), None))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn docker_config() {
+		let config: DockerConfig = serde_json::from_str(
+			r#"{
+				"auths": {
+					"https://index.docker.io/v1/": {"auth": "dXNlcjpodW50ZXIy"},
+					"myregistry.azurecr.io": {"auth": "MDAwMDAwMDAtMDAwMC0wMDAwLTAwMDAtMDAwMDAwMDAwMDAwOg==", "identitytoken": "refresh"}
+				},
+				"credHelpers": {"123456789012.dkr.ecr.us-east-1.amazonaws.com": "ecr-login"}
+			}"#
+		)
+		.unwrap();
+		let hub = config.auths.iter().find(|(server, _)| registry_key(server) == registry_key("registry-1.docker.io")).unwrap().1;
+		let hub = decode(hub).unwrap();
+		assert_eq!(hub.username.as_ref(), "user");
+		assert_eq!(hub.password.as_ref(), "hunter2");
+		let acr = decode(&config.auths["myregistry.azurecr.io"]).unwrap();
+		assert_eq!(acr.username.as_ref(), "00000000-0000-0000-0000-000000000000");
+		assert_eq!(acr.password.as_ref(), "refresh");
+		assert_eq!(config.cred_helpers.keys().map(|k| registry_key(k)).collect::<Vec<_>>(), ["123456789012.dkr.ecr.us-east-1.amazonaws.com"]);
+		assert_eq!(server_url("registry-1.docker.io"), "https://index.docker.io/v1/");
+	}
+}
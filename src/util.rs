@@ -24,6 +24,13 @@ impl From<&str> for SecretString {
 	}
 }
 
+impl AsRef<str> for SecretString {
+	#[inline]
+	fn as_ref(&self) -> &str {
+		self.0.as_ref()
+	}
+}
+
 impl fmt::Debug for SecretString {
 	#[inline]
 	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {